@@ -1,4 +1,7 @@
 pub mod csc_measurement;
 pub mod cycling_power_measurement;
+pub mod fitness_machine;
 pub mod heart_rate_measurement;
 pub mod revolution_data;
+pub mod trainer_control;
+pub mod wahoo_trainer;
@@ -63,7 +63,10 @@ pub fn checked_wheel_rpm_and_new_count(
 ) -> Option<(f64, u32)> {
     let a = a.wheel.as_ref();
     let b = b.wheel.as_ref();
-    crate::utils::lift_a2_option(a, b, checked_rpm_and_new_count_rev_data).and_then(|x| x)
+    crate::utils::lift_a2_option(a, b, |a, b| {
+        checked_rpm_and_new_count_rev_data(a, b, CSC_TIME_ROLLOVER, CSC_COUNT_ROLLOVER)
+    })
+    .and_then(|x| x)
 }
 
 pub fn checked_crank_rpm_and_new_count(
@@ -72,13 +75,26 @@ pub fn checked_crank_rpm_and_new_count(
 ) -> Option<(f64, u32)> {
     let a = a.crank.as_ref();
     let b = b.crank.as_ref();
-    crate::utils::lift_a2_option(a, b, checked_rpm_and_new_count_rev_data).and_then(|x| x)
+    crate::utils::lift_a2_option(a, b, |a, b| {
+        checked_rpm_and_new_count_rev_data(a, b, CSC_TIME_ROLLOVER, CSC_COUNT_ROLLOVER)
+    })
+    .and_then(|x| x)
 }
 
+// Event times are in 1/1024s, so they roll over every 64s.  For cranks, the
+// count takes a _long_ time to overflow, but it can happen.  For wheels, this is
+// essentially impossible (>8.5M km ride), so we treat both the same.
+const CSC_TIME_ROLLOVER: f64 = 64.0;
+const CSC_COUNT_ROLLOVER: u64 = 0x10000;
+
 // TODO: How to better handle overflow when managing raw/decoded data
-fn checked_rpm_and_new_count_rev_data(
+// Event times roll over every time_rollover seconds and counts at
+// count_rollover, which differ between CSC and cycling power.
+pub fn checked_rpm_and_new_count_rev_data(
     a: &RevolutionData,
     b: &RevolutionData,
+    time_rollover: f64,
+    count_rollover: u64,
 ) -> Option<(f64, u32)> {
     if a.last_revolution_event_time == b.last_revolution_event_time {
         None
@@ -86,17 +102,14 @@ fn checked_rpm_and_new_count_rev_data(
         let duration = if b.last_revolution_event_time > a.last_revolution_event_time {
             b.last_revolution_event_time - a.last_revolution_event_time
         } else {
-            0b1000000 as f64 + b.last_revolution_event_time - a.last_revolution_event_time
+            time_rollover + b.last_revolution_event_time - a.last_revolution_event_time
         };
 
-        // For cranks, this takes a _long_ time to overflow, but it can happen.
-        // For wheels, this is essentially impossible (>8.5M km ride), so this
-        // if condition will simply never occur.
-        let new_revolutions = if b.revolution_count > a.revolution_count {
-            b.revolution_count - a.revolution_count
+        let new_revolutions = if b.revolution_count >= a.revolution_count {
+            b.revolution_count as u64 - a.revolution_count as u64
         } else {
-            0x10000 + b.revolution_count - a.revolution_count
-        };
+            count_rollover + b.revolution_count as u64 - a.revolution_count as u64
+        } as u32;
 
         Some((new_revolutions as f64 * 60.0 / duration, new_revolutions))
     }
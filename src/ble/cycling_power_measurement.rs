@@ -1,3 +1,4 @@
+use crate::ble::csc_measurement::checked_rpm_and_new_count_rev_data;
 use crate::ble::revolution_data::RevolutionData;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -13,6 +14,9 @@ pub struct CyclingPowerMeasurement {
     // Notably this is _truly_ a percent, not a rate
     // conversion to rate would be lossly
     pub pedal_power_balance_percent: Option<f32>,
+    // Whether the balance is the left pedal's share, otherwise we don't know
+    // which pedal it is
+    pub is_pedal_power_balance_left: bool,
     // Sum of the average torque measured per source rotation. Divide by
     // rotations to get average torque or multiply by 2pi to get total energy.
     // If you know the gearing you can translate from one source to the other.
@@ -31,11 +35,34 @@ impl CyclingPowerMeasurement {
             |a, b| b - a + if a > b { 2048.0 } else { 0.0 },
         )
     }
+
+    // Wheel event times are in 1/2048s, so they roll over every 32s, and the
+    // count is a full 32 bits.
+    pub fn checked_wheel_rpm_and_new_count(&self, next: &Self) -> Option<(f64, u32)> {
+        crate::utils::lift_a2_option(
+            self.wheel_revolution_data.as_ref(),
+            next.wheel_revolution_data.as_ref(),
+            |a, b| checked_rpm_and_new_count_rev_data(a, b, 32.0, 0x1_0000_0000),
+        )
+        .and_then(|x| x)
+    }
+
+    // Crank event times are in 1/1024s, so they roll over every 64s, and the
+    // count is only 16 bits.
+    pub fn checked_crank_rpm_and_new_count(&self, next: &Self) -> Option<(f64, u32)> {
+        crate::utils::lift_a2_option(
+            self.crank_revolution_data.as_ref(),
+            next.crank_revolution_data.as_ref(),
+            |a, b| checked_rpm_and_new_count_rev_data(a, b, 64.0, 0x10000),
+        )
+        .and_then(|x| x)
+    }
 }
 
 // Notably, this function always assumes a valid input
 pub fn parse_cycling_power_measurement(data: &Vec<u8>) -> CyclingPowerMeasurement {
     let has_pedal_power_balance = data[0] & 1 == 1;
+    let is_pedal_power_balance_left = data[0] & 0b10 == 0b10;
     let has_accumulated_torque = data[0] & 0b100 == 0b100;
     let has_wheel_data = data[0] & 0b10000 == 0b10000;
     let has_crank_data = data[0] & 0b100000 == 0b100000;
@@ -53,6 +80,7 @@ pub fn parse_cycling_power_measurement(data: &Vec<u8>) -> CyclingPowerMeasuremen
         } else {
             None
         },
+        is_pedal_power_balance_left,
         accumulated_torque: if has_accumulated_torque {
             let source = if data[0] & 0b1000 == 0b1000 {
                 AccumulatedTorqueSource::Crank
@@ -121,6 +149,7 @@ mod tests {
             CyclingPowerMeasurement {
                 instantaneous_power: 0x0102,
                 pedal_power_balance_percent: Some(49.5),
+                is_pedal_power_balance_left: false,
                 accumulated_torque: Some((AccumulatedTorqueSource::Wheel, 0x0201 as f64 / 32.0)),
                 wheel_revolution_data: Some(RevolutionData {
                     revolution_count: 0x04030201,
@@ -143,6 +172,7 @@ mod tests {
             CyclingPowerMeasurement {
                 instantaneous_power: 0x0102,
                 pedal_power_balance_percent: None,
+                is_pedal_power_balance_left: false,
                 accumulated_torque: Some((AccumulatedTorqueSource::Crank, 0x0201 as f64 / 32.0)),
                 wheel_revolution_data: None,
                 crank_revolution_data: Some(RevolutionData {
@@ -160,6 +190,7 @@ mod tests {
             CyclingPowerMeasurement {
                 instantaneous_power: 0x0102,
                 pedal_power_balance_percent: None,
+                is_pedal_power_balance_left: false,
                 accumulated_torque: Some((AccumulatedTorqueSource::Wheel, 0x0201 as f64 / 32.0)),
                 wheel_revolution_data: Some(RevolutionData {
                     revolution_count: 0x04030201,
@@ -177,6 +208,7 @@ mod tests {
             CyclingPowerMeasurement {
                 instantaneous_power: 0x0102,
                 pedal_power_balance_percent: Some(49.5),
+                is_pedal_power_balance_left: false,
                 accumulated_torque: None,
                 wheel_revolution_data: None,
                 crank_revolution_data: None,
@@ -185,12 +217,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_cpm_with_left_pedal_power_balance() {
+        assert_eq!(
+            CyclingPowerMeasurement {
+                instantaneous_power: 0x0102,
+                pedal_power_balance_percent: Some(49.5),
+                is_pedal_power_balance_left: true,
+                accumulated_torque: None,
+                wheel_revolution_data: None,
+                crank_revolution_data: None,
+            },
+            parse_cycling_power_measurement(&vec!(0b11, 0, 2, 1, 99))
+        );
+    }
+
     #[test]
     fn parse_cpm_empty() {
         assert_eq!(
             CyclingPowerMeasurement {
                 instantaneous_power: 0x0102,
                 pedal_power_balance_percent: None,
+                is_pedal_power_balance_left: false,
                 accumulated_torque: None,
                 wheel_revolution_data: None,
                 crank_revolution_data: None,
@@ -198,4 +246,26 @@ mod tests {
             parse_cycling_power_measurement(&vec!(0, 0, 2, 1))
         );
     }
+
+    #[test]
+    fn crank_rpm_and_new_count_with_overflow() {
+        let a = parse_cycling_power_measurement(&vec![0b100000, 0, 2, 1, 0xfe, 0xff, 0x00, 0xf8]);
+        let b = parse_cycling_power_measurement(&vec![0b100000, 0, 2, 1, 0x01, 0x00, 0x00, 0x04]);
+        // 3 revolutions in 3 seconds
+        assert_eq!(Some((60.0, 3)), a.checked_crank_rpm_and_new_count(&b));
+        assert_eq!(None, a.checked_wheel_rpm_and_new_count(&b));
+    }
+
+    #[test]
+    fn wheel_rpm_and_new_count_with_overflow() {
+        let a = parse_cycling_power_measurement(&vec![
+            0b10000, 0, 2, 1, 0xff, 0xff, 0xff, 0xff, 0x00, 0xfc,
+        ]);
+        let b = parse_cycling_power_measurement(&vec![
+            0b10000, 0, 2, 1, 0x03, 0x00, 0x00, 0x00, 0x00, 0x04,
+        ]);
+        // 4 revolutions in 1 second
+        assert_eq!(Some((240.0, 4)), a.checked_wheel_rpm_and_new_count(&b));
+        assert_eq!(None, a.checked_crank_rpm_and_new_count(&b));
+    }
 }
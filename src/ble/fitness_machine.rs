@@ -0,0 +1,212 @@
+use crate::ble::trainer_control::{Response, SimSettings};
+use btleplug::api::UUID;
+
+// The standard Fitness Machine Service (FTMS), which most newer smart trainers
+// speak instead of (or as well as) something proprietary.
+
+// Advertised by anything that speaks FTMS
+pub const SERVICE_UUID: UUID = UUID::B16(0x1826);
+pub const INDOOR_BIKE_DATA_UUID: UUID = UUID::B16(0x2AD2);
+pub const CONTROL_POINT_UUID: UUID = UUID::B16(0x2AD9);
+
+const REQUEST_CONTROL: u8 = 0x00;
+const SET_TARGET_RESISTANCE: u8 = 0x04;
+const SET_TARGET_POWER: u8 = 0x05;
+const SET_SIMULATION: u8 = 0x11;
+
+// The control point answers each command with this, followed by the opcode
+// it's answering and a result code.
+const RESPONSE: u8 = 0x80;
+const SUCCESS: u8 = 0x01;
+
+// Resistance levels are unitless, with a resolution of 0.1, so a single byte
+// can't go any higher than this
+const MAX_RESISTANCE_LEVEL: f32 = 25.5;
+
+// A Struct that does not care about bit compression.  Speed is in m/s, cadence
+// in rpm, and distance in meters.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndoorBikeData {
+    pub instantaneous_speed: Option<f32>,
+    pub instantaneous_cadence: Option<f32>,
+    pub total_distance: Option<u32>,
+    pub resistance_level: Option<i16>,
+    pub instantaneous_power: Option<i16>,
+    pub heart_rate: Option<u8>,
+    // TODO: There are other fields, but they're averages, energy, and times
+}
+
+// Notably, this function always assumes a valid input
+pub fn parse_indoor_bike_data(data: &Vec<u8>) -> IndoorBikeData {
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    let has = |bit: u16| flags & (1 << bit) != 0;
+    let mut index = 2;
+    let mut field = |present: bool, size: usize| {
+        if present {
+            let start = index;
+            index += size;
+            Some(&data[start..index])
+        } else {
+            None
+        }
+    };
+
+    // Unlike every other flag, speed is present when its bit is _not_ set.  It's
+    // in units of 0.01km/h.
+    let instantaneous_speed =
+        field(!has(0), 2).map(|x| u16::from_le_bytes([x[0], x[1]]) as f32 / 360.0);
+    let _average_speed = field(has(1), 2);
+    let instantaneous_cadence =
+        field(has(2), 2).map(|x| u16::from_le_bytes([x[0], x[1]]) as f32 / 2.0);
+    let _average_cadence = field(has(3), 2);
+    let total_distance = field(has(4), 3).map(|x| u32::from_le_bytes([x[0], x[1], x[2], 0]));
+    let resistance_level = field(has(5), 2).map(|x| i16::from_le_bytes([x[0], x[1]]));
+    let instantaneous_power = field(has(6), 2).map(|x| i16::from_le_bytes([x[0], x[1]]));
+    let _average_power = field(has(7), 2);
+    // Total, per hour, and per minute
+    let _expended_energy = field(has(8), 5);
+    let heart_rate = field(has(9), 1).map(|x| x[0]);
+
+    IndoorBikeData {
+        instantaneous_speed,
+        instantaneous_cadence,
+        total_distance,
+        resistance_level,
+        instantaneous_power,
+        heart_rate,
+    }
+}
+
+// Nothing else is accepted until we've asked to be in control
+pub fn request_control_command() -> Vec<u8> {
+    vec![REQUEST_CONTROL]
+}
+
+pub fn target_power_command(power: u16) -> Vec<u8> {
+    let mut command = vec![SET_TARGET_POWER];
+    command.extend_from_slice(&(power.min(i16::max_value() as u16) as i16).to_le_bytes());
+    command
+}
+
+// Our percent is spread across every level the command can express
+pub fn resistance_command(percent: f32) -> Vec<u8> {
+    let level = percent.max(0.0).min(100.0) / 100.0 * MAX_RESISTANCE_LEVEL;
+    vec![SET_TARGET_RESISTANCE, (level * 10.0).round() as u8]
+}
+
+// FTMS has no notion of weight, and takes the grade along with the rest of the
+// model (with no wind).
+pub fn sim_command(settings: &SimSettings, grade: f32) -> Vec<u8> {
+    let scaled_u8 =
+        |value: f32, scale: f32| (value * scale).round().max(0.0).min(u8::max_value() as f32) as u8;
+    let mut command = vec![SET_SIMULATION];
+    command.extend_from_slice(&0i16.to_le_bytes());
+    command.extend_from_slice(
+        &((grade * 100.0)
+            .round()
+            .max(i16::min_value() as f32)
+            .min(i16::max_value() as f32) as i16)
+            .to_le_bytes(),
+    );
+    command.push(scaled_u8(settings.rolling_resistance, 10000.0));
+    command.push(scaled_u8(settings.wind_resistance, 100.0));
+    command
+}
+
+// Returns None for anything that isn't a response to a command
+pub fn parse_response(value: &[u8]) -> Option<Response> {
+    match value {
+        [RESPONSE, opcode, result, data @ ..] => Some(Response {
+            opcode: *opcode,
+            success: *result == SUCCESS,
+            data: data.to_vec(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_indoor_bike_data;
+    use super::parse_response;
+    use super::resistance_command;
+    use super::sim_command;
+    use super::target_power_command;
+    use super::IndoorBikeData;
+    use crate::ble::trainer_control::{Response, SimSettings};
+
+    #[test]
+    fn parse_indoor_bike_data_speed_cadence_and_power() {
+        // 36km/h, 90rpm and 250W
+        assert_eq!(
+            parse_indoor_bike_data(&vec![0x44, 0x00, 0x10, 0x0e, 0xb4, 0x00, 0xfa, 0x00]),
+            IndoorBikeData {
+                instantaneous_speed: Some(10.0),
+                instantaneous_cadence: Some(90.0),
+                total_distance: None,
+                resistance_level: None,
+                instantaneous_power: Some(250),
+                heart_rate: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_indoor_bike_data_skips_averages_and_energy() {
+        assert_eq!(
+            parse_indoor_bike_data(&vec![
+                0xbb, 0x03, 1, 2, 3, 4, 0x01, 0x02, 0x03, 0x0a, 0x00, 0x64, 0x00, 9, 9, 1, 2, 3,
+                140,
+            ]),
+            IndoorBikeData {
+                instantaneous_speed: None,
+                instantaneous_cadence: None,
+                total_distance: Some(0x030201),
+                resistance_level: Some(10),
+                instantaneous_power: None,
+                heart_rate: Some(140),
+            }
+        );
+    }
+
+    #[test]
+    fn target_power_command_250w() {
+        assert_eq!(target_power_command(250), vec![0x05, 0xfa, 0x00]);
+    }
+
+    #[test]
+    fn resistance_command_range() {
+        assert_eq!(resistance_command(0.0), vec![0x04, 0]);
+        assert_eq!(resistance_command(50.0), vec![0x04, 128]);
+        assert_eq!(resistance_command(120.0), vec![0x04, 255]);
+    }
+
+    #[test]
+    fn sim_command_default_at_5_percent() {
+        assert_eq!(
+            sim_command(&SimSettings::default(), 5.0),
+            vec![0x11, 0x00, 0x00, 0xf4, 0x01, 40, 51]
+        );
+        assert_eq!(
+            sim_command(&SimSettings::default(), -2.5),
+            vec![0x11, 0x00, 0x00, 0x06, 0xff, 40, 51]
+        );
+    }
+
+    #[test]
+    fn parse_response_power_ack() {
+        assert_eq!(
+            parse_response(&[0x80, 0x05, 0x01]),
+            Some(Response {
+                opcode: 0x05,
+                success: true,
+                data: vec![],
+            })
+        );
+        assert_eq!(
+            parse_response(&[0x80, 0x11, 0x02]).map(|r| r.success),
+            Some(false)
+        );
+        assert_eq!(parse_response(&[0x05, 0xfa, 0x00]), None);
+    }
+}
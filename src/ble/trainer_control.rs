@@ -0,0 +1,33 @@
+// What's shared between the ways we control trainers (Wahoo's own protocol and
+// FTMS), independent of how either encodes it.
+
+// The physical model the trainer uses to turn grade into resistance
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SimSettings {
+    // Rider and bike together, in kg
+    pub weight: f32,
+    // Coefficient of rolling resistance (unitless)
+    pub rolling_resistance: f32,
+    // Half of air density times drag area (in kg/m)
+    pub wind_resistance: f32,
+}
+
+impl Default for SimSettings {
+    // A typical rider on the hoods, on a road bike with road tires
+    fn default() -> SimSettings {
+        SimSettings {
+            weight: 85.0,
+            rolling_resistance: 0.004,
+            wind_resistance: 0.51,
+        }
+    }
+}
+
+// A trainer's answer to one of our commands
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub opcode: u8,
+    pub success: bool,
+    // Anything after the status, which depends on the opcode
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,189 @@
+use crate::ble::trainer_control::{Response, SimSettings};
+use std::time::Duration;
+
+// Commands for the Wahoo trainer control characteristic (which predates FTMS).
+// Each starts with an opcode, followed by little endian parameters.
+
+const SET_RESISTANCE_MODE: u8 = 0x40;
+const SET_ERG_MODE: u8 = 0x42;
+const SET_SIM_MODE: u8 = 0x43;
+const SET_GRADE: u8 = 0x46;
+const INIT_SPINDOWN: u8 = 0x49;
+
+// The trainer answers each command with a notification that starts with this,
+// followed by the opcode it's answering and whether it succeeded.
+const RESPONSE: u8 = 0x01;
+const SUCCESS: u8 = 0x01;
+
+// Stages of a spindown, as reported in the first byte of its responses
+const SPINDOWN_SPEED_UP: u8 = 0x00;
+const SPINDOWN_COAST: u8 = 0x01;
+const SPINDOWN_COMPLETE: u8 = 0x02;
+
+fn with_u16(opcode: u8, values: &[u16]) -> Vec<u8> {
+    let mut command = vec![opcode];
+    for v in values {
+        command.extend_from_slice(&v.to_le_bytes());
+    }
+    command
+}
+
+fn scaled(value: f32, scale: f32) -> u16 {
+    (value * scale)
+        .round()
+        .max(0.0)
+        .min(u16::max_value() as f32) as u16
+}
+
+pub fn erg_command(power: u16) -> Vec<u8> {
+    with_u16(SET_ERG_MODE, &[power])
+}
+
+// Resistance is a percent of the brake's maximum, but the trainer counts down
+// from 16383 (which is no resistance).
+pub fn resistance_command(percent: f32) -> Vec<u8> {
+    let fraction = percent.max(0.0).min(100.0) / 100.0;
+    with_u16(SET_RESISTANCE_MODE, &[scaled(1.0 - fraction, 16383.0)])
+}
+
+pub fn sim_command(settings: &SimSettings) -> Vec<u8> {
+    with_u16(
+        SET_SIM_MODE,
+        &[
+            scaled(settings.weight, 100.0),
+            scaled(settings.rolling_resistance, 10000.0),
+            scaled(settings.wind_resistance, 1000.0),
+        ],
+    )
+}
+
+// Grade is a percent, where -100% to 100% map onto the full range of a u16
+// (so flat is 0x8000).
+pub fn grade_command(percent: f32) -> Vec<u8> {
+    let fraction = percent.max(-100.0).min(100.0) / 100.0;
+    with_u16(SET_GRADE, &[scaled(fraction + 1.0, 32768.0)])
+}
+
+pub fn spindown_command() -> Vec<u8> {
+    vec![INIT_SPINDOWN]
+}
+
+// Returns None for anything that isn't a response to a command
+pub fn parse_response(value: &[u8]) -> Option<Response> {
+    match value {
+        [RESPONSE, opcode, status, data @ ..] => Some(Response {
+            opcode: *opcode,
+            success: *status == SUCCESS,
+            data: data.to_vec(),
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpindownStatus {
+    // The rider should pedal until the trainer tells them to stop
+    SpeedUp,
+    // The rider should stop pedaling and let the flywheel coast
+    Coast,
+    // How long the flywheel took to slow down
+    Complete(Duration),
+    Failed,
+}
+
+// Returns None for responses to anything other than a spindown
+pub fn parse_spindown(response: &Response) -> Option<SpindownStatus> {
+    if response.opcode != INIT_SPINDOWN {
+        return None;
+    }
+    Some(match (response.success, &response.data[..]) {
+        (true, [SPINDOWN_SPEED_UP, ..]) => SpindownStatus::SpeedUp,
+        (true, [SPINDOWN_COAST, ..]) => SpindownStatus::Coast,
+        // Time is in milliseconds
+        (true, [SPINDOWN_COMPLETE, lsb, msb, ..]) => SpindownStatus::Complete(
+            Duration::from_millis(u16::from_le_bytes([*lsb, *msb]) as u64),
+        ),
+        _ => SpindownStatus::Failed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::erg_command;
+    use super::grade_command;
+    use super::parse_response;
+    use super::parse_spindown;
+    use super::resistance_command;
+    use super::sim_command;
+    use super::SpindownStatus;
+    use crate::ble::trainer_control::{Response, SimSettings};
+    use std::time::Duration;
+
+    #[test]
+    fn erg_command_250w() {
+        assert_eq!(erg_command(250), vec![0x42, 0xfa, 0x00]);
+    }
+
+    #[test]
+    fn resistance_command_range() {
+        assert_eq!(resistance_command(0.0), vec![0x40, 0xff, 0x3f]);
+        assert_eq!(resistance_command(50.0), vec![0x40, 0x00, 0x20]);
+        assert_eq!(resistance_command(100.0), vec![0x40, 0x00, 0x00]);
+        assert_eq!(resistance_command(120.0), vec![0x40, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn sim_command_default() {
+        // 8500, 40 and 510
+        assert_eq!(
+            sim_command(&SimSettings::default()),
+            vec![0x43, 0x34, 0x21, 0x28, 0x00, 0xfe, 0x01]
+        );
+    }
+
+    #[test]
+    fn grade_command_range() {
+        assert_eq!(grade_command(0.0), vec![0x46, 0x00, 0x80]);
+        assert_eq!(grade_command(5.0), vec![0x46, 0x66, 0x86]);
+        assert_eq!(grade_command(-100.0), vec![0x46, 0x00, 0x00]);
+        assert_eq!(grade_command(100.0), vec![0x46, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn parse_response_erg_ack() {
+        assert_eq!(
+            parse_response(&[0x01, 0x42, 0x01]),
+            Some(Response {
+                opcode: 0x42,
+                success: true,
+                data: vec![],
+            })
+        );
+        assert_eq!(parse_response(&[0x42, 0xfa, 0x00]), None);
+        assert_eq!(parse_response(&[0x01]), None);
+    }
+
+    #[test]
+    fn parse_spindown_stages() {
+        let spindown = |value: &[u8]| parse_spindown(&parse_response(value).unwrap());
+        assert_eq!(
+            spindown(&[0x01, 0x49, 0x01, 0x00]),
+            Some(SpindownStatus::SpeedUp)
+        );
+        assert_eq!(
+            spindown(&[0x01, 0x49, 0x01, 0x01]),
+            Some(SpindownStatus::Coast)
+        );
+        assert_eq!(
+            spindown(&[0x01, 0x49, 0x01, 0x02, 0xd2, 0x04]),
+            Some(SpindownStatus::Complete(Duration::from_millis(1234)))
+        );
+        // A complete spindown that's missing its time is no good to us
+        assert_eq!(
+            spindown(&[0x01, 0x49, 0x01, 0x02]),
+            Some(SpindownStatus::Failed)
+        );
+        assert_eq!(spindown(&[0x01, 0x49, 0x00]), Some(SpindownStatus::Failed));
+        assert_eq!(spindown(&[0x01, 0x42, 0x01]), None);
+    }
+}
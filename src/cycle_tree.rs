@@ -30,6 +30,18 @@ fn flatten<L: Copy>(cycle_tree: &CycleTree<L>, v: &mut Vec<L>) {
     }
 }
 
+impl<L> CycleTree<L> {
+    // Transforms every leaf, keeping the same structure
+    pub fn map<M, F: Fn(&L) -> M>(&self, f: &F) -> CycleTree<M> {
+        match self {
+            CycleTree::Leaf(l) => CycleTree::Leaf(f(l)),
+            CycleTree::Node((c, ws)) => {
+                CycleTree::Node((*c, ws.iter().map(|w| w.map(f)).collect()))
+            }
+        }
+    }
+}
+
 // TODO: Make this more efficient, but notably gets the right interface in place
 impl<L: Copy> IntoIterator for CycleTree<L> {
     type Item = L;
@@ -85,4 +97,25 @@ mod tests {
         .collect();
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn map_keeps_structure() {
+        assert_eq!(
+            CycleTree::Node((
+                2,
+                vec![
+                    CycleTree::Node((3, vec![CycleTree::Leaf(2)])),
+                    CycleTree::Leaf(1),
+                ],
+            ))
+            .map(&|x| x * 10),
+            CycleTree::Node((
+                2,
+                vec![
+                    CycleTree::Node((3, vec![CycleTree::Leaf(20)])),
+                    CycleTree::Leaf(10),
+                ],
+            ))
+        );
+    }
 }
@@ -2,6 +2,7 @@
 use crate::memory_lcd::MemoryLcd;
 #[cfg(feature = "simulator")]
 use crate::memory_lcd_simulator::MemoryLcd;
+use crate::workout::WorkoutProgress;
 use chrono::Local;
 use embedded_graphics::{
     drawable::Drawable,
@@ -15,6 +16,9 @@ use embedded_graphics::{
 };
 use std::time::{Duration, Instant};
 
+// Readings older than this are no longer shown (or trusted)
+pub const STALE_AFTER: Duration = Duration::from_secs(5);
+
 pub struct Display {
     memory_lcd: MemoryLcd,
     workout: WorkoutDisplay,
@@ -64,6 +68,14 @@ impl Display {
         self.workout.set_gps_fix(has_fix);
     }
 
+    pub fn update_workout_progress(&mut self, progress: Option<WorkoutProgress>) {
+        self.workout.update_workout_progress(progress);
+    }
+
+    pub fn update_gear(&mut self, gear: Option<(usize, usize)>) {
+        self.workout.update_gear(gear);
+    }
+
     pub fn render_msg(&mut self, s: &str) {
         self.memory_lcd.clear(BinaryColor::Off).unwrap();
         self.has_rendered = false;
@@ -102,6 +114,9 @@ pub struct WorkoutDisplay {
     speed: Option<(f32, Instant)>,
     distance: f64,
     gps_fix: Option<(bool, Instant)>,
+    workout_progress: Option<WorkoutProgress>,
+    // The current virtual gear and how many there are
+    gear: Option<(usize, usize)>,
     start_instant: Instant,
 }
 
@@ -116,6 +131,8 @@ impl WorkoutDisplay {
             speed: None,
             distance: 0.0,
             gps_fix: None,
+            workout_progress: None,
+            gear: None,
             start_instant,
         }
     }
@@ -151,6 +168,14 @@ impl WorkoutDisplay {
     pub fn set_gps_fix(&mut self, has_fix: bool) {
         self.gps_fix = Some((has_fix, Instant::now()));
     }
+
+    pub fn update_workout_progress(&mut self, progress: Option<WorkoutProgress>) {
+        self.workout_progress = progress;
+    }
+
+    pub fn update_gear(&mut self, gear: Option<(usize, usize)>) {
+        self.gear = gear;
+    }
 }
 
 impl Drawable<BinaryColor> for WorkoutDisplay {
@@ -313,6 +338,105 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
         .into_styled(style_large)
         .draw(target)?;
 
+        if let Some(progress) = self.workout_progress {
+            let top = 8 + 6 + 16 + 2 + 6 + 16 + 2 + 6 + 16 + 2;
+
+            Text::new(
+                &if progress.is_paused {
+                    "PAUSED".to_string()
+                } else if progress.step >= progress.step_count {
+                    "STEP TAIL".to_string()
+                } else {
+                    format!("STEP {}/{}", progress.step + 1, progress.step_count)
+                },
+                geometry::Point::new(8 + 50, top),
+            )
+            .into_styled(style_tiny)
+            .draw(target)?;
+
+            Text::new(
+                &format!("{:03}W", progress.target),
+                geometry::Point::new(8 + 50, top + 6),
+            )
+            .into_styled(style_large)
+            .draw(target)?;
+
+            Text::new("LEFT", geometry::Point::new(8 + 50, top + 6 + 16 + 2))
+                .into_styled(style_tiny)
+                .draw(target)?;
+
+            Text::new(
+                &progress.remaining.map_or("--:--".to_string(), |x| {
+                    let secs = x.as_secs();
+                    format!("{:02}:{:02}", secs / 60, secs % 60)
+                }),
+                geometry::Point::new(8 + 50, top + 6 + 16 + 2 + 6),
+            )
+            .into_styled(style_large)
+            .draw(target)?;
+
+            Text::new(
+                "NEXT",
+                geometry::Point::new(8 + 50, top + 6 + 16 + 2 + 6 + 16 + 2),
+            )
+            .into_styled(style_tiny)
+            .draw(target)?;
+
+            Text::new(
+                &progress
+                    .next_target
+                    .map_or("----".to_string(), |x| format!("{:03}W", x)),
+                geometry::Point::new(8 + 50, top + 6 + 16 + 2 + 6 + 16 + 2 + 6),
+            )
+            .into_styled(style_large)
+            .draw(target)?;
+
+            // A bar for how far through the whole workout we are
+            let bar_top = top + 6 + 16 + 2 + 6 + 16 + 2 + 6 + 16 + 2;
+            let bar_width = 76;
+            Rectangle::new(
+                geometry::Point::new(8 + 50, bar_top),
+                geometry::Point::new(8 + 50 + bar_width, bar_top + 6),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .stroke_color(BinaryColor::On)
+                    .stroke_width(1)
+                    .fill_color(BinaryColor::Off)
+                    .build(),
+            )
+            .draw(target)?;
+            Rectangle::new(
+                geometry::Point::new(8 + 50, bar_top),
+                geometry::Point::new(
+                    8 + 50 + (bar_width as f64 * progress.fraction()) as i32,
+                    bar_top + 6,
+                ),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(BinaryColor::On)
+                    .stroke_width(0)
+                    .build(),
+            )
+            .draw(target)?;
+        }
+
+        if let Some((gear, gear_count)) = self.gear {
+            let top = 8 + 6 + 16 + 2 + 6 + 16 + 2 + 6 + 16 + 2;
+
+            Text::new("GEAR", geometry::Point::new(8 + 50, top))
+                .into_styled(style_tiny)
+                .draw(target)?;
+
+            Text::new(
+                &format!("{:02}/{:02}", gear, gear_count),
+                geometry::Point::new(8 + 50, top + 6),
+            )
+            .into_styled(style_large)
+            .draw(target)?;
+        }
+
         Rectangle::new(geometry::Point::new(187, 3), geometry::Point::new(193, 9))
             .into_styled(
                 PrimitiveStyleBuilder::new()
@@ -390,7 +514,7 @@ impl<'a, 'b> Drawable<BinaryColor> for OptionDisplay<'a, 'b> {
 }
 
 fn none_if_stale<T>(x: (T, Instant)) -> Option<(T, Instant)> {
-    if x.1.elapsed() > Duration::from_secs(5) {
+    if x.1.elapsed() > STALE_AFTER {
         None
     } else {
         Some(x)
@@ -0,0 +1,302 @@
+use crate::cycle_tree::CycleTree;
+use crate::workout::{Power, Target, Workout};
+use std::time::Duration;
+
+// Anything past these is a broken file rather than a hard workout
+const MAX_MINUTES: f64 = 24.0 * 60.0;
+const MAX_WATTS: f64 = u16::max_value() as f64;
+const MAX_PERCENT: f64 = 300.0;
+
+// Line numbers start at 1, so they match what a text editor shows
+#[derive(Debug, PartialEq)]
+pub enum ErgError {
+    MissingCourseData,
+    UnterminatedCourseData(usize),
+    MissingUnits,
+    UnknownUnits(usize),
+    InvalidRow(usize),
+    OutOfRange(usize),
+    TimeGoesBackwards(usize),
+    TooFewRows(usize),
+}
+
+impl std::fmt::Display for ErgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ErgError::MissingCourseData => write!(f, "No [COURSE DATA] section"),
+            ErgError::UnterminatedCourseData(line) => {
+                write!(f, "Line {}: [COURSE DATA] is never ended", line)
+            }
+            ErgError::MissingUnits => write!(f, "No MINUTES WATTS or MINUTES PERCENT header"),
+            ErgError::UnknownUnits(line) => write!(f, "Line {}: Unknown units", line),
+            ErgError::InvalidRow(line) => {
+                write!(f, "Line {}: Expected minutes and a target", line)
+            }
+            ErgError::OutOfRange(line) => {
+                write!(f, "Line {}: Minutes or target is out of range", line)
+            }
+            ErgError::TimeGoesBackwards(line) => {
+                write!(f, "Line {}: Minutes are less than the row before", line)
+            }
+            ErgError::TooFewRows(line) => {
+                write!(f, "Line {}: Course data needs at least two rows", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ErgError {}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Units {
+    Watts,
+    PercentOfFtp,
+}
+
+impl Units {
+    fn max_target(self) -> f64 {
+        match self {
+            Units::Watts => MAX_WATTS,
+            Units::PercentOfFtp => MAX_PERCENT,
+        }
+    }
+}
+
+fn parse_row(line: &str, line_number: usize, max_target: f64) -> Result<(f64, f64), ErgError> {
+    let values: Vec<f64> = line
+        .split_whitespace()
+        .map(|x| x.parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| ErgError::InvalidRow(line_number))?;
+    // Comparisons are false for NaN, so it's out of range too
+    let is_in_range = |x: f64, max: f64| x >= 0.0 && x <= max;
+    match values[..] {
+        [minutes, target]
+            if is_in_range(minutes, MAX_MINUTES) && is_in_range(target, max_target) =>
+        {
+            Ok((minutes, target))
+        }
+        [_, _] => Err(ErgError::OutOfRange(line_number)),
+        _ => Err(ErgError::InvalidRow(line_number)),
+    }
+}
+
+// Parses an .erg (absolute watts) or .mrc (percent of FTP) file into its
+// description and a Workout, using the units declared in its header.  Each
+// pair of rows is a segment, which is steady if both targets are the same and
+// a ramp otherwise.  Rows at the same minute are an instant change of target.
+pub fn parse_erg(text: &str) -> Result<(String, Workout), ErgError> {
+    let mut name = String::new();
+    let mut units = None;
+    let mut course_data_start = None;
+    let mut rows: Vec<(usize, f64, f64)> = Vec::new();
+    let mut is_terminated = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        let upper = line.to_ascii_uppercase();
+        if course_data_start.is_some() {
+            if upper == "[END COURSE DATA]" {
+                is_terminated = true;
+                break;
+            } else if !line.is_empty() {
+                // The header comes first, so units are known by now (and if
+                // not, the file is rejected for that after this loop)
+                let max_target = units.map_or(MAX_WATTS, Units::max_target);
+                let (minutes, target) = parse_row(line, line_number, max_target)?;
+                rows.push((line_number, minutes, target));
+            }
+        } else if upper == "[COURSE DATA]" {
+            course_data_start = Some(line_number);
+        } else if upper.starts_with("MINUTES") {
+            units = match upper.split_whitespace().nth(1) {
+                Some("WATTS") => Some(Units::Watts),
+                Some("PERCENT") => Some(Units::PercentOfFtp),
+                _ => return Err(ErgError::UnknownUnits(line_number)),
+            };
+        } else if let Some(eq) = line.find('=') {
+            let key = upper[..eq].trim();
+            let value = line[eq + 1..].trim();
+            // A description is nicer, but a file name is better than nothing
+            if key == "DESCRIPTION" || (key == "FILE NAME" && name.is_empty()) {
+                name = value.to_string();
+            }
+        }
+    }
+
+    let course_data_start = course_data_start.ok_or(ErgError::MissingCourseData)?;
+    if !is_terminated {
+        return Err(ErgError::UnterminatedCourseData(course_data_start));
+    }
+    let units = units.ok_or(ErgError::MissingUnits)?;
+    if rows.len() < 2 {
+        return Err(ErgError::TooFewRows(course_data_start));
+    }
+
+    let to_power = |target: f64| match units {
+        Units::Watts => Power::Watts(target.round() as u16),
+        Units::PercentOfFtp => Power::FtpPercent(target as f32),
+    };
+
+    let mut steps = Vec::new();
+    for pair in rows.windows(2) {
+        let (_, from_minutes, from_target) = pair[0];
+        let (line_number, to_minutes, to_target) = pair[1];
+        if to_minutes < from_minutes {
+            return Err(ErgError::TimeGoesBackwards(line_number));
+        }
+        let duration = Duration::from_secs_f64((to_minutes - from_minutes) * 60.0);
+        if duration > Duration::from_secs(0) {
+            steps.push(CycleTree::Leaf((
+                duration,
+                if from_target == to_target {
+                    Target::Steady(to_power(from_target))
+                } else {
+                    Target::Ramp(to_power(from_target), to_power(to_target))
+                },
+            )));
+        }
+    }
+
+    Ok((name, Workout::new(CycleTree::Node((1, steps)), None)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_erg, ErgError};
+    use crate::fit::FitWorkoutStep;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_erg_watts() {
+        let (name, workout) = parse_erg(
+            "[COURSE HEADER]
+VERSION = 2
+UNITS = ENGLISH
+DESCRIPTION = Sweet Spot
+FILE NAME = sweet_spot.erg
+MINUTES WATTS
+[END COURSE HEADER]
+[COURSE DATA]
+0.00\t100
+0.50\t200
+
+0.50\t250
+1.00\t250
+[END COURSE DATA]
+",
+        )
+        .unwrap();
+
+        assert_eq!(name, "Sweet Spot");
+        assert_eq!(
+            workout.to_fit_steps(300),
+            vec![
+                FitWorkoutStep::Power(117, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(150, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(183, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(250, Some(Duration::from_secs(30))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_mrc_percent() {
+        let (name, workout) = parse_erg(
+            "[COURSE HEADER]
+FILE NAME = threshold.mrc
+MINUTES PERCENT
+[END COURSE HEADER]
+[COURSE DATA]
+0 50
+2 50
+2 105
+10 105
+[END COURSE DATA]",
+        )
+        .unwrap();
+
+        assert_eq!(name, "threshold.mrc");
+        assert_eq!(
+            workout.to_fit_steps(200),
+            vec![
+                FitWorkoutStep::Power(100, Some(Duration::from_secs(120))),
+                FitWorkoutStep::Power(210, Some(Duration::from_secs(480))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_erg_invalid_row() {
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n5 lots\n[END COURSE DATA]").err(),
+            Some(ErgError::InvalidRow(4))
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100 5\n[END COURSE DATA]").err(),
+            Some(ErgError::InvalidRow(3))
+        );
+    }
+
+    #[test]
+    fn parse_erg_out_of_range() {
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\ninf 100\n[END COURSE DATA]").err(),
+            Some(ErgError::OutOfRange(4))
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n1e300 100\n[END COURSE DATA]").err(),
+            Some(ErgError::OutOfRange(4))
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 NaN\n5 100\n[END COURSE DATA]").err(),
+            Some(ErgError::OutOfRange(3))
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n5 -1\n[END COURSE DATA]").err(),
+            Some(ErgError::OutOfRange(4))
+        );
+    }
+
+    #[test]
+    fn parse_mrc_out_of_range() {
+        assert_eq!(
+            parse_erg("MINUTES PERCENT\n[COURSE DATA]\n0 300\n5 301\n[END COURSE DATA]").err(),
+            Some(ErgError::OutOfRange(4))
+        );
+        assert!(parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 300\n5 301\n[END COURSE DATA]").is_ok());
+    }
+
+    #[test]
+    fn parse_erg_time_goes_backwards() {
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n5 100\n4 200\n[END COURSE DATA]").err(),
+            Some(ErgError::TimeGoesBackwards(5))
+        );
+    }
+
+    #[test]
+    fn parse_erg_structural_errors() {
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n").err(),
+            Some(ErgError::MissingCourseData)
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n5 100\n").err(),
+            Some(ErgError::UnterminatedCourseData(2))
+        );
+        assert_eq!(
+            parse_erg("[COURSE DATA]\n0 100\n5 100\n[END COURSE DATA]").err(),
+            Some(ErgError::MissingUnits)
+        );
+        assert_eq!(
+            parse_erg("MINUTES SPEED\n").err(),
+            Some(ErgError::UnknownUnits(1))
+        );
+        assert_eq!(
+            parse_erg("MINUTES WATTS\n[COURSE DATA]\n0 100\n[END COURSE DATA]").err(),
+            Some(ErgError::TooFewRows(2))
+        );
+    }
+}
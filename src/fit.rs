@@ -1,6 +1,8 @@
 // This is just a quick port of the original JS I had written--there's room for
 // improvement
 
+use std::time::Duration;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct FitRecord {
     // We use the same bitdepth, but not the same epoch
@@ -21,6 +23,79 @@ pub struct FitRecord {
     pub distance: Option<f64>,
     // Instantaneous speed in meters/s
     pub speed: Option<f32>,
+    // Percent of power from one pedal.  Sensors often can't tell which pedal
+    // is which, so (as in FIT) this is from the right pedal if the 0x80 bit is
+    // set, and an unknown pedal otherwise.
+    pub left_right_balance: Option<u8>,
+    // Cumulative energy output in Joules (what FIT calls watts)
+    pub accumulated_power: Option<u32>,
+    // Cumulative crank revolutions
+    pub total_cycles: Option<u32>,
+}
+
+// Seconds between the unix epoch and the FIT epoch (1989-12-31T00:00:00Z)
+const FIT_EPOCH_OFFSET: u32 = 631065600;
+
+#[derive(Debug, PartialEq)]
+pub enum FitError {
+    // The file ended before its header, a message, or its CRC was complete
+    Truncated,
+    // The header is too short or is missing the ".FIT" signature
+    InvalidHeader,
+    // The CRC stored at the end of the file doesn't match the file contents
+    BadCrc { expected: u16, calculated: u16 },
+    // A field definition uses a base type that the FIT profile doesn't define
+    UnknownBaseType(u8),
+    // A data message uses a local message type that was never defined
+    UndefinedLocalMessage(u8),
+}
+
+impl std::fmt::Display for FitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FitError::Truncated => write!(f, "FIT file is truncated"),
+            FitError::InvalidHeader => write!(f, "FIT file has an invalid header"),
+            FitError::BadCrc {
+                expected,
+                calculated,
+            } => write!(
+                f,
+                "FIT file CRC mismatch (expected {:#06x}, calculated {:#06x})",
+                expected, calculated
+            ),
+            FitError::UnknownBaseType(t) => write!(f, "Unknown FIT base type {:#04x}", t),
+            FitError::UndefinedLocalMessage(l) => {
+                write!(f, "FIT data message uses undefined local type {}", l)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FitError {}
+
+// A lap begins at its start and lasts until the next lap (or the end of the
+// activity).  Records from before the first lap are considered part of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitLap {
+    // Same epoch as FitRecord
+    pub start: u32,
+    // The wattage the trainer was asked to hold during this lap
+    pub target_power: Option<u16>,
+    // How long this lap was planned to last, None if it was open ended
+    pub planned_duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitActivity {
+    pub records: Vec<FitRecord>,
+    // If empty, the whole activity is one lap
+    pub laps: Vec<FitLap>,
+    // Every RR interval (in seconds) in order, each paired with the time
+    // (same epoch as FitRecord) that it was received.
+    pub rr_intervals: Vec<(u32, f32)>,
+    // When the rider paused and resumed (same epoch as FitRecord) in order, or
+    // None if they never resumed.  The timer stops while paused.
+    pub pauses: Vec<(u32, Option<u32>)>,
 }
 
 fn make_header(length: usize) -> Vec<u8> {
@@ -46,7 +121,7 @@ fn make_header(length: usize) -> Vec<u8> {
 }
 
 fn record_to_bytes(record: &FitRecord) -> Vec<u8> {
-    let ts = record.seconds_since_unix_epoch - 631065600;
+    let ts = record.seconds_since_unix_epoch - FIT_EPOCH_OFFSET;
     let mut bytes = vec![
         // Type 0 (we only ever define type 0, and just continually change the definition)
         0,
@@ -95,6 +170,18 @@ fn record_to_bytes(record: &FitRecord) -> Vec<u8> {
         bytes.extend(&x);
     }
 
+    if let Some(b) = record.left_right_balance {
+        bytes.push(b);
+    }
+
+    if let Some(p) = record.accumulated_power {
+        bytes.extend(&u32::to_le_bytes(p));
+    }
+
+    if let Some(c) = record.total_cycles {
+        bytes.extend(&u32::to_le_bytes(c));
+    }
+
     bytes
 }
 
@@ -111,7 +198,22 @@ fn record_def(record: &FitRecord) -> Vec<u8> {
         }
         + if let Some(_) = record.cadence { 1 } else { 0 }
         + if let Some(_) = record.distance { 1 } else { 0 }
-        + if let Some(_) = record.speed { 1 } else { 0 };
+        + if let Some(_) = record.speed { 1 } else { 0 }
+        + if let Some(_) = record.left_right_balance {
+            1
+        } else {
+            0
+        }
+        + if let Some(_) = record.accumulated_power {
+            1
+        } else {
+            0
+        }
+        + if let Some(_) = record.total_cycles {
+            1
+        } else {
+            0
+        };
 
     let mut bytes = vec![
         // Field definition for message type 0
@@ -163,6 +265,18 @@ fn record_def(record: &FitRecord) -> Vec<u8> {
         // Speed (field definition number, byte count, default type (u16))
         6, 2, 0x84,
     ];
+    let balance_def = vec![
+        // Left/Right Balance (field definition number, byte count, default type (u8))
+        30, 1, 2,
+    ];
+    let accumulated_power_def = vec![
+        // Accumulated Power (field definition number, byte count, default type (u32))
+        29, 4, 0x86,
+    ];
+    let total_cycles_def = vec![
+        // Total Cycles (field definition number, byte count, default type (u32))
+        19, 4, 0x86,
+    ];
 
     if let Some(_) = record.latitude {
         bytes.extend(lat_def);
@@ -196,10 +310,22 @@ fn record_def(record: &FitRecord) -> Vec<u8> {
         bytes.extend(speed_def);
     }
 
+    if let Some(_) = record.left_right_balance {
+        bytes.extend(balance_def);
+    }
+
+    if let Some(_) = record.accumulated_power {
+        bytes.extend(accumulated_power_def);
+    }
+
+    if let Some(_) = record.total_cycles {
+        bytes.extend(total_cycles_def);
+    }
+
     bytes
 }
 
-fn calculate_crc(blob: &Vec<u8>) -> u16 {
+fn calculate_crc(blob: &[u8]) -> u16 {
     let crc_table = [
         0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401, 0xa001, 0x6c00, 0x7800,
         0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
@@ -219,11 +345,42 @@ fn calculate_crc(blob: &Vec<u8>) -> u16 {
     crc
 }
 
-fn to_file_inner(list: &Vec<FitRecord>) -> Vec<u8> {
+// RR intervals are written as HRV messages just before the first record that
+// is at or after when they were received.
+// Timer events are each a time and whether the timer starts (or stops) then,
+// and they're written just before the first record at or after that time.
+fn to_file_inner(
+    list: &Vec<FitRecord>,
+    rr_intervals: &[(u32, f32)],
+    timer_events: &[(u32, bool)],
+) -> Vec<u8> {
     let mut bytes = Vec::new();
     let mut last_def: Option<Vec<u8>> = None;
+    let mut is_hrv_defined = false;
+    let mut rr_index = 0;
+    let mut timer_index = 0;
 
     for record in list {
+        while timer_index < timer_events.len()
+            && timer_events[timer_index].0 <= record.seconds_since_unix_epoch
+        {
+            let (timestamp, is_start) = timer_events[timer_index];
+            bytes.extend(timer_event_to_bytes(timestamp, is_start));
+            timer_index += 1;
+        }
+
+        let rr_start = rr_index;
+        while rr_index < rr_intervals.len()
+            && rr_intervals[rr_index].0 <= record.seconds_since_unix_epoch
+        {
+            rr_index += 1;
+        }
+        let rrs: Vec<f32> = rr_intervals[rr_start..rr_index]
+            .iter()
+            .map(|x| x.1)
+            .collect();
+        bytes.extend(hrv_to_bytes(&rrs, &mut is_hrv_defined));
+
         let new_def = record_def(record);
         match last_def {
             Some(ld) => {
@@ -243,11 +400,16 @@ fn to_file_inner(list: &Vec<FitRecord>) -> Vec<u8> {
         bytes.extend(record_to_bytes(record));
     }
 
+    let rrs: Vec<f32> = rr_intervals[rr_index..].iter().map(|x| x.1).collect();
+    bytes.extend(hrv_to_bytes(&rrs, &mut is_hrv_defined));
+    for (timestamp, is_start) in &timer_events[timer_index..] {
+        bytes.extend(timer_event_to_bytes(*timestamp, *is_start));
+    }
+
     bytes
 }
 
-pub fn to_file(list: &Vec<FitRecord>) -> Vec<u8> {
-    let record_buffer = to_file_inner(list);
+fn with_header_and_crc(record_buffer: Vec<u8>) -> Vec<u8> {
     let mut bytes = make_header(record_buffer.len());
     bytes.extend(record_buffer);
     let crc = calculate_crc(&bytes);
@@ -255,10 +417,949 @@ pub fn to_file(list: &Vec<FitRecord>) -> Vec<u8> {
     bytes
 }
 
+pub fn to_file(list: &Vec<FitRecord>) -> Vec<u8> {
+    with_header_and_crc(to_file_inner(list, &[], &[]))
+}
+
+// A single field of a non-record message.  Records are hot enough (and
+// uniform enough) that they get their own encoder, but everything else is
+// written once or twice per file, so we favor flexibility.
+struct Field {
+    number: u8,
+    base_type: u8,
+    bytes: Vec<u8>,
+}
+
+fn enum_field(number: u8, value: u8) -> Field {
+    Field {
+        number,
+        base_type: 0x00,
+        bytes: vec![value],
+    }
+}
+
+fn u8_field(number: u8, value: u8) -> Field {
+    Field {
+        number,
+        base_type: 0x02,
+        bytes: vec![value],
+    }
+}
+
+fn u16_field(number: u8, value: u16) -> Field {
+    Field {
+        number,
+        base_type: 0x84,
+        bytes: value.to_le_bytes().to_vec(),
+    }
+}
+
+fn i32_field(number: u8, value: i32) -> Field {
+    Field {
+        number,
+        base_type: 0x85,
+        bytes: value.to_le_bytes().to_vec(),
+    }
+}
+
+fn u32_field(number: u8, value: u32) -> Field {
+    Field {
+        number,
+        base_type: 0x86,
+        bytes: value.to_le_bytes().to_vec(),
+    }
+}
+
+// Strings are null terminated, and their size includes the terminator
+fn string_field(number: u8, value: &str) -> Field {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    Field {
+        number,
+        base_type: 0x07,
+        bytes,
+    }
+}
+
+fn timestamp_field(seconds_since_unix_epoch: u32) -> Field {
+    u32_field(253, seconds_since_unix_epoch - FIT_EPOCH_OFFSET)
+}
+
+fn to_semicircles(degrees: f64) -> i32 {
+    (degrees * ((1 << 29) as f64) / 45.0) as i32
+}
+
+fn definition_to_bytes(
+    local_message_type: u8,
+    global_message_number: u16,
+    fields: &Vec<Field>,
+) -> Vec<u8> {
+    let mut bytes = vec![
+        // Field definition for the local message type
+        0x40 | local_message_type,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        (global_message_number & 0xff) as u8,
+        (global_message_number >> 8) as u8,
+        fields.len() as u8,
+    ];
+
+    for field in fields {
+        bytes.extend(&[field.number, field.bytes.len() as u8, field.base_type]);
+    }
+
+    bytes
+}
+
+fn data_to_bytes(local_message_type: u8, fields: &Vec<Field>) -> Vec<u8> {
+    let mut bytes = vec![local_message_type];
+    for field in fields {
+        bytes.extend(&field.bytes);
+    }
+    bytes
+}
+
+// Writes both the definition and the data for a message.  Records always use
+// local message type 0 and HRV uses 2, so anything else should use local type
+// 1 so that those definitions can be reused after it.
+fn message_to_bytes(
+    local_message_type: u8,
+    global_message_number: u16,
+    fields: &Vec<Field>,
+) -> Vec<u8> {
+    let mut bytes = definition_to_bytes(local_message_type, global_message_number, fields);
+    bytes.extend(data_to_bytes(local_message_type, fields));
+    bytes
+}
+
+// HRV messages hold up to five RR intervals (in ms), so we always use five,
+// padding with invalid values, so that the definition only needs to be
+// written once.
+fn hrv_to_bytes(rr_intervals: &[f32], is_defined: &mut bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for chunk in rr_intervals.chunks(5) {
+        let mut time = Vec::with_capacity(10);
+        for i in 0..5 {
+            let ms = chunk
+                .get(i)
+                .map_or(0xffff, |rr| (rr * 1000.0).round() as u16);
+            time.extend(&ms.to_le_bytes());
+        }
+        let fields = vec![Field {
+            number: 0,
+            base_type: 0x84,
+            bytes: time,
+        }];
+        if !*is_defined {
+            bytes.extend(definition_to_bytes(2, 78, &fields));
+            *is_defined = true;
+        }
+        bytes.extend(data_to_bytes(2, &fields));
+    }
+    bytes
+}
+
+// Totals and extremes across a span of records, used for laps and sessions
+struct Summary {
+    start_time: u32,
+    end_time: u32,
+    // Seconds between the start and end that weren't paused
+    timer_time: u32,
+    start_position: Option<(f64, f64)>,
+    end_position: Option<(f64, f64)>,
+    distance: Option<f64>,
+    avg_speed: Option<f32>,
+    max_speed: Option<f32>,
+    avg_heart_rate: Option<u8>,
+    max_heart_rate: Option<u8>,
+    avg_cadence: Option<u8>,
+    max_cadence: Option<u8>,
+    avg_power: Option<u16>,
+    max_power: Option<u16>,
+}
+
+fn average<T: Copy + Into<f64>>(xs: &Vec<T>) -> Option<f64> {
+    if xs.is_empty() {
+        None
+    } else {
+        Some(xs.iter().map(|&x| x.into()).sum::<f64>() / xs.len() as f64)
+    }
+}
+
+fn maximum<T: Copy + PartialOrd>(xs: &Vec<T>) -> Option<T> {
+    xs.iter().fold(None, |acc, &x| match acc {
+        Some(m) if m >= x => Some(m),
+        _ => Some(x),
+    })
+}
+
+// How much of the time from start to end was spent paused
+fn paused_time(pauses: &[(u32, Option<u32>)], start: u32, end: u32) -> u32 {
+    pauses
+        .iter()
+        .map(|(paused, resumed)| {
+            let to = resumed.unwrap_or(end).min(end);
+            to.saturating_sub((*paused).max(start))
+        })
+        .sum()
+}
+
+// Distance is cumulative, so the distance covered is relative to the last
+// distance recorded before these records.  The span ends at end_time if it's
+// given, otherwise at the last record.
+fn summarize(
+    records: &[FitRecord],
+    prior_distance: f64,
+    end_time: Option<u32>,
+    pauses: &[(u32, Option<u32>)],
+) -> Option<Summary> {
+    let first = records.first()?;
+    let last = records.last()?;
+    let start_time = first.seconds_since_unix_epoch;
+    let end_time = end_time.unwrap_or(last.seconds_since_unix_epoch);
+    let positions: Vec<(f64, f64)> = records
+        .iter()
+        .filter_map(|r| crate::utils::lift_a2_option(r.latitude, r.longitude, |a, b| (a, b)))
+        .collect();
+    let speeds: Vec<f32> = records.iter().filter_map(|r| r.speed).collect();
+    let heart_rates: Vec<u8> = records.iter().filter_map(|r| r.heart_rate).collect();
+    let cadences: Vec<u8> = records.iter().filter_map(|r| r.cadence).collect();
+    let powers: Vec<u16> = records.iter().filter_map(|r| r.power).collect();
+
+    Some(Summary {
+        start_time,
+        end_time,
+        timer_time: (end_time - start_time)
+            .saturating_sub(paused_time(pauses, start_time, end_time)),
+        start_position: positions.first().cloned(),
+        end_position: positions.last().cloned(),
+        distance: records
+            .iter()
+            .rev()
+            .find_map(|r| r.distance)
+            .map(|d| d - prior_distance),
+        avg_speed: average(&speeds).map(|x| x as f32),
+        max_speed: maximum(&speeds),
+        avg_heart_rate: average(&heart_rates).map(|x| x.round() as u8),
+        max_heart_rate: maximum(&heart_rates),
+        avg_cadence: average(&cadences).map(|x| x.round() as u8),
+        max_cadence: maximum(&cadences),
+        avg_power: average(&powers).map(|x| x.round() as u16),
+        max_power: maximum(&powers),
+    })
+}
+
+// Lap and session messages share most of their fields and numbers, but lap
+// has one less field before avg_speed, so everything from it onwards is
+// relative to avg_speed's number.
+fn summary_fields(summary: &Summary, avg_speed_number: u8) -> Vec<Field> {
+    let mut fields = vec![
+        timestamp_field(summary.end_time),
+        u32_field(2, summary.start_time - FIT_EPOCH_OFFSET),
+        // Total elapsed time
+        u32_field(7, (summary.end_time - summary.start_time) * 1000),
+        // Total timer time, which stops while paused
+        u32_field(8, summary.timer_time * 1000),
+    ];
+
+    if let Some(d) = summary.distance {
+        fields.push(u32_field(9, (100.0 * d) as u32));
+    }
+    if let Some(s) = summary.avg_speed {
+        fields.push(u16_field(avg_speed_number, (1000.0 * s) as u16));
+    }
+    if let Some(s) = summary.max_speed {
+        fields.push(u16_field(avg_speed_number + 1, (1000.0 * s) as u16));
+    }
+    if let Some(hr) = summary.avg_heart_rate {
+        fields.push(u8_field(avg_speed_number + 2, hr));
+    }
+    if let Some(hr) = summary.max_heart_rate {
+        fields.push(u8_field(avg_speed_number + 3, hr));
+    }
+    if let Some(c) = summary.avg_cadence {
+        fields.push(u8_field(avg_speed_number + 4, c));
+    }
+    if let Some(c) = summary.max_cadence {
+        fields.push(u8_field(avg_speed_number + 5, c));
+    }
+    if let Some(p) = summary.avg_power {
+        fields.push(u16_field(avg_speed_number + 6, p));
+    }
+    if let Some(p) = summary.max_power {
+        fields.push(u16_field(avg_speed_number + 7, p));
+    }
+    if let Some((lat, lon)) = summary.start_position {
+        fields.push(i32_field(3, to_semicircles(lat)));
+        fields.push(i32_field(4, to_semicircles(lon)));
+    }
+
+    fields
+}
+
+fn lap_to_bytes(
+    summary: &Summary,
+    message_index: u16,
+    workout_step_index: Option<u16>,
+    is_last: bool,
+) -> Vec<u8> {
+    let mut fields = summary_fields(summary, 13);
+    fields.extend(vec![
+        u16_field(254, message_index),
+        // Event: lap
+        enum_field(0, 9),
+        // Event type: stop
+        enum_field(1, 1),
+        // Lap trigger: session end or time
+        enum_field(24, if is_last { 7 } else { 1 }),
+        // Sport: cycling
+        enum_field(25, 2),
+    ]);
+    if let Some((lat, lon)) = summary.end_position {
+        fields.push(i32_field(5, to_semicircles(lat)));
+        fields.push(i32_field(6, to_semicircles(lon)));
+    }
+    if let Some(i) = workout_step_index {
+        fields.push(u16_field(71, i));
+    }
+    message_to_bytes(1, 19, &fields)
+}
+
+fn workout_to_bytes(name: Option<&str>, step_count: u16) -> Vec<u8> {
+    let mut fields = vec![
+        // Sport: cycling
+        enum_field(4, 2),
+        // Number of valid steps
+        u16_field(6, step_count),
+    ];
+    if let Some(n) = name {
+        fields.push(string_field(8, n));
+    }
+    message_to_bytes(1, 26, &fields)
+}
+
+// A workout step that holds a power, either for a duration or indefinitely
+fn power_step_to_bytes(message_index: u16, power: u16, duration: Option<Duration>) -> Vec<u8> {
+    let mut fields = vec![u16_field(254, message_index)];
+    match duration {
+        Some(d) => {
+            // Duration type: time (in ms)
+            fields.push(enum_field(1, 0));
+            fields.push(u32_field(2, d.as_millis() as u32));
+        }
+        // Duration type: open
+        None => fields.push(enum_field(1, 5)),
+    }
+    fields.extend(vec![
+        // Target type: power
+        enum_field(3, 4),
+        // Target value of zero means the custom range is used
+        u32_field(4, 0),
+        // Custom powers are offset by 1000 (lower values are %FTP)
+        u32_field(5, power as u32 + 1000),
+        u32_field(6, power as u32 + 1000),
+        // Intensity: active
+        enum_field(7, 0),
+    ]);
+    message_to_bytes(1, 27, &fields)
+}
+
+fn session_to_bytes(summary: &Summary, lap_count: u16) -> Vec<u8> {
+    let mut fields = summary_fields(summary, 14);
+    fields.extend(vec![
+        u16_field(254, 0),
+        // Event: session
+        enum_field(0, 8),
+        // Event type: stop
+        enum_field(1, 1),
+        // Sport: cycling
+        enum_field(5, 2),
+        // Sub sport: generic
+        enum_field(6, 0),
+        // First lap index
+        u16_field(25, 0),
+        u16_field(26, lap_count),
+        // Trigger: activity end
+        enum_field(28, 0),
+    ]);
+    message_to_bytes(1, 18, &fields)
+}
+
+fn activity_to_bytes(summary: &Summary) -> Vec<u8> {
+    let fields = vec![
+        timestamp_field(summary.end_time),
+        // Total timer time
+        u32_field(0, summary.timer_time * 1000),
+        // Number of sessions
+        u16_field(1, 1),
+        // Type: manual
+        enum_field(2, 0),
+        // Event: activity
+        enum_field(3, 26),
+        // Event type: stop
+        enum_field(4, 1),
+    ];
+    message_to_bytes(1, 34, &fields)
+}
+
+// File types are 4 for an activity and 5 for a workout
+fn file_id_to_bytes(file_type: u8, time_created: Option<u32>) -> Vec<u8> {
+    let mut fields = vec![
+        enum_field(0, file_type),
+        // Manufacturer: development
+        u16_field(1, 255),
+        // Product
+        u16_field(2, 0),
+    ];
+    if let Some(t) = time_created {
+        fields.push(u32_field(4, t - FIT_EPOCH_OFFSET));
+    }
+    message_to_bytes(1, 0, &fields)
+}
+
+fn device_info_to_bytes(timestamp: u32) -> Vec<u8> {
+    // Software version is scaled by 100, so 0.2.x becomes 0.20
+    let major: u16 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    let minor: u16 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    let fields = vec![
+        timestamp_field(timestamp),
+        // Device index: creator
+        u8_field(0, 0),
+        // Manufacturer: development
+        u16_field(2, 255),
+        // Product
+        u16_field(4, 0),
+        u16_field(5, major * 100 + minor),
+    ];
+    message_to_bytes(1, 23, &fields)
+}
+
+fn timer_event_to_bytes(timestamp: u32, is_start: bool) -> Vec<u8> {
+    let fields = vec![
+        timestamp_field(timestamp),
+        // Event: timer
+        enum_field(0, 0),
+        // Event type: start or stop all
+        enum_field(1, if is_start { 0 } else { 4 }),
+        // Event group
+        u8_field(4, 0),
+    ];
+    message_to_bytes(1, 21, &fields)
+}
+
+// Repeats every step from the given index up to this one, going through them a
+// total of count times.
+fn repeat_step_to_bytes(message_index: u16, from: u16, count: u32) -> Vec<u8> {
+    let fields = vec![
+        u16_field(254, message_index),
+        // Duration type: repeat until steps complete
+        enum_field(1, 6),
+        // The step index to repeat from
+        u32_field(2, from as u32),
+        // Target value is the repetition count for repeat steps
+        u32_field(4, count),
+    ];
+    message_to_bytes(1, 27, &fields)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FitWorkoutStep {
+    // Hold a wattage for a duration, or indefinitely if there's no duration
+    Power(u16, Option<Duration>),
+    // Go back to the step at this index, for a total of count times through
+    Repeat(u16, u32),
+}
+
+// Creates a workout file that other head units can run as a structured
+// workout.
+pub fn to_workout_file(name: &str, steps: &Vec<FitWorkoutStep>) -> Vec<u8> {
+    let mut record_buffer = file_id_to_bytes(5, None);
+    record_buffer.extend(workout_to_bytes(Some(name), steps.len() as u16));
+    for (i, step) in steps.iter().enumerate() {
+        record_buffer.extend(match step {
+            FitWorkoutStep::Power(power, duration) => {
+                power_step_to_bytes(i as u16, *power, *duration)
+            }
+            FitWorkoutStep::Repeat(from, count) => repeat_step_to_bytes(i as u16, *from, *count),
+        });
+    }
+    with_header_and_crc(record_buffer)
+}
+
+// Split records into laps (skipping any laps that have none), pairing each
+// with the FitLap that started it, if there was one.
+fn split_laps(activity: &FitActivity) -> Vec<(Option<&FitLap>, &[FitRecord])> {
+    let list = &activity.records;
+    let mut laps: Vec<(Option<&FitLap>, &[FitRecord])> = Vec::new();
+    let mut lap_start_index = 0;
+    for (i, lap) in activity.laps.iter().enumerate() {
+        let lap_end_index = match activity.laps.get(i + 1) {
+            Some(next) => list
+                .iter()
+                .position(|r| r.seconds_since_unix_epoch >= next.start)
+                .unwrap_or(list.len()),
+            None => list.len(),
+        };
+        if lap_end_index > lap_start_index {
+            laps.push((Some(lap), &list[lap_start_index..lap_end_index]));
+            lap_start_index = lap_end_index;
+        }
+    }
+    if laps.is_empty() && !list.is_empty() {
+        laps.push((None, &list[..]));
+    }
+    laps
+}
+
+// Summarizes each lap from split_laps, with distances relative to the start of
+// each lap.  Each lap lasts until the next one starts (and the last until the
+// end of the activity), so that together they add up to the whole activity.
+fn summarize_laps(activity: &FitActivity) -> Vec<(Option<&FitLap>, &[FitRecord], Summary)> {
+    let mut prior_distance = 0.0;
+    let laps = split_laps(activity);
+    let next_starts: Vec<Option<u32>> = laps
+        .iter()
+        .skip(1)
+        .map(|(_, records)| Some(records[0].seconds_since_unix_epoch))
+        .chain(std::iter::once(None))
+        .collect();
+    laps.into_iter()
+        .zip(next_starts)
+        .map(|((lap, records), next_start)| {
+            // Laps are never empty, so they always have a summary
+            let summary = summarize(records, prior_distance, next_start, &activity.pauses).unwrap();
+            if let Some(d) = summary.distance {
+                prior_distance += d;
+            }
+            (lap, records, summary)
+        })
+        .collect()
+}
+
+// Just what's needed to describe a lap in formats other than FIT
+pub struct LapSummary<'a> {
+    pub lap: Option<&'a FitLap>,
+    pub records: &'a [FitRecord],
+    pub start_time: u32,
+    pub end_time: u32,
+    // Seconds that weren't paused
+    pub timer_time: u32,
+    pub distance: Option<f64>,
+    pub avg_speed: Option<f32>,
+    pub max_speed: Option<f32>,
+    pub avg_heart_rate: Option<u8>,
+    pub max_heart_rate: Option<u8>,
+    pub avg_cadence: Option<u8>,
+    pub avg_power: Option<u16>,
+    pub max_power: Option<u16>,
+}
+
+pub fn lap_summaries(activity: &FitActivity) -> Vec<LapSummary<'_>> {
+    summarize_laps(activity)
+        .into_iter()
+        .map(|(lap, records, summary)| LapSummary {
+            lap,
+            records,
+            start_time: summary.start_time,
+            end_time: summary.end_time,
+            timer_time: summary.timer_time,
+            distance: summary.distance,
+            avg_speed: summary.avg_speed,
+            max_speed: summary.max_speed,
+            avg_heart_rate: summary.avg_heart_rate,
+            max_heart_rate: summary.max_heart_rate,
+            avg_cadence: summary.avg_cadence,
+            avg_power: summary.avg_power,
+            max_power: summary.max_power,
+        })
+        .collect()
+}
+
+// Unlike to_file, this creates a complete activity that can be uploaded to
+// sites like Strava or Garmin Connect.  The records are wrapped with a file_id,
+// device_info, timer start/stop events (including for each pause), and then
+// laps, a session, and an activity that summarize them.  Laps with a target
+// power also get a workout step, so that the target is shown alongside the
+// lap.
+pub fn to_activity_file(activity: &FitActivity) -> Vec<u8> {
+    let list = &activity.records;
+    let summary = summarize(list, 0.0, None, &activity.pauses);
+    let mut record_buffer = file_id_to_bytes(4, summary.as_ref().map(|s| s.start_time));
+    let laps = summarize_laps(activity);
+
+    let steps: Vec<(u16, Option<Duration>)> = laps
+        .iter()
+        .filter_map(|(lap, _, _)| lap.and_then(|l| l.target_power.map(|p| (p, l.planned_duration))))
+        .collect();
+
+    if let Some(s) = &summary {
+        record_buffer.extend(device_info_to_bytes(s.start_time));
+        if !steps.is_empty() {
+            record_buffer.extend(workout_to_bytes(None, steps.len() as u16));
+            for (i, (power, duration)) in steps.iter().enumerate() {
+                record_buffer.extend(power_step_to_bytes(i as u16, *power, *duration));
+            }
+        }
+        record_buffer.extend(timer_event_to_bytes(s.start_time, true));
+    }
+
+    let timer_events: Vec<(u32, bool)> = activity
+        .pauses
+        .iter()
+        .flat_map(|(paused, resumed)| {
+            std::iter::once((*paused, false)).chain(resumed.map(|r| (r, true)))
+        })
+        .collect();
+    record_buffer.extend(to_file_inner(list, &activity.rr_intervals, &timer_events));
+
+    if let Some(s) = &summary {
+        // The timer is already stopped if we finished while paused
+        if activity.pauses.last().map_or(true, |(_, r)| r.is_some()) {
+            record_buffer.extend(timer_event_to_bytes(s.end_time, false));
+        }
+        let mut step_index = 0;
+        for (i, (lap, _, lap_summary)) in laps.iter().enumerate() {
+            let workout_step_index = if lap.and_then(|l| l.target_power).is_some() {
+                step_index += 1;
+                Some(step_index - 1)
+            } else {
+                None
+            };
+            record_buffer.extend(lap_to_bytes(
+                lap_summary,
+                i as u16,
+                workout_step_index,
+                i == laps.len() - 1,
+            ));
+        }
+        record_buffer.extend(session_to_bytes(s, laps.len() as u16));
+        record_buffer.extend(activity_to_bytes(s));
+    }
+
+    with_header_and_crc(record_buffer)
+}
+
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+    base_type: u8,
+}
+
+struct MessageDefinition {
+    global_message_number: u16,
+    is_big_endian: bool,
+    fields: Vec<FieldDefinition>,
+    // We never interpret developer fields, we just need to know how many bytes
+    // to skip over.
+    developer_data_size: usize,
+}
+
+fn take<'a>(bytes: &'a [u8], index: &mut usize, count: usize) -> Result<&'a [u8], FitError> {
+    match bytes.get(*index..*index + count) {
+        Some(taken) => {
+            *index += count;
+            Ok(taken)
+        }
+        None => Err(FitError::Truncated),
+    }
+}
+
+// The size in bytes of a single value of each base type, or None if the base
+// type is not one from the FIT profile.
+fn base_type_size(base_type: u8) -> Option<usize> {
+    match base_type {
+        // enum, sint8, uint8, string, uint8z, byte
+        0x00 | 0x01 | 0x02 | 0x07 | 0x0a | 0x0d => Some(1),
+        // sint16, uint16, uint16z
+        0x83 | 0x84 | 0x8b => Some(2),
+        // sint32, uint32, float32, uint32z
+        0x85 | 0x86 | 0x88 | 0x8c => Some(4),
+        // float64, sint64, uint64, uint64z
+        0x89 | 0x8e | 0x8f | 0x90 => Some(8),
+        _ => None,
+    }
+}
+
+// Reads the first value of a field as an f64 (which is lossless for every
+// type but the 64 bit integers), returning None for the type's invalid value.
+// Arrays only yield their first element and strings are not interpreted.
+fn decode_value(bytes: &[u8], base_type: u8, is_big_endian: bool) -> Option<f64> {
+    let size = base_type_size(base_type)?;
+    if bytes.len() < size {
+        return None;
+    }
+
+    let mut raw: u64 = 0;
+    for i in 0..size {
+        let byte = if is_big_endian {
+            bytes[i]
+        } else {
+            bytes[size - 1 - i]
+        };
+        raw = (raw << 8) | byte as u64;
+    }
+
+    match base_type {
+        0x00 | 0x02 | 0x0d if raw != 0xff => Some(raw as f64),
+        0x01 if raw != 0x7f => Some(raw as u8 as i8 as f64),
+        0x83 if raw != 0x7fff => Some(raw as u16 as i16 as f64),
+        0x84 if raw != 0xffff => Some(raw as f64),
+        0x85 if raw != 0x7fff_ffff => Some(raw as u32 as i32 as f64),
+        0x86 if raw != 0xffff_ffff => Some(raw as f64),
+        0x88 if raw != 0xffff_ffff => Some(f32::from_bits(raw as u32) as f64),
+        0x89 if raw != u64::max_value() => Some(f64::from_bits(raw)),
+        0x0a | 0x8b | 0x8c | 0x90 if raw != 0 => Some(raw as f64),
+        0x8e if raw != 0x7fff_ffff_ffff_ffff => Some(raw as i64 as f64),
+        0x8f if raw != u64::max_value() => Some(raw as f64),
+        _ => None,
+    }
+}
+
+fn decode_definition(
+    bytes: &[u8],
+    index: &mut usize,
+    has_developer_data: bool,
+) -> Result<MessageDefinition, FitError> {
+    let fixed = take(bytes, index, 5)?;
+    // fixed[0] is reserved
+    let is_big_endian = fixed[1] == 1;
+    let global_message_number = if is_big_endian {
+        u16::from_be_bytes([fixed[2], fixed[3]])
+    } else {
+        u16::from_le_bytes([fixed[2], fixed[3]])
+    };
+
+    let field_count = fixed[4] as usize;
+    let mut fields = Vec::with_capacity(field_count);
+    for _ in 0..field_count {
+        let field = take(bytes, index, 3)?;
+        if let None = base_type_size(field[2]) {
+            return Err(FitError::UnknownBaseType(field[2]));
+        }
+        fields.push(FieldDefinition {
+            number: field[0],
+            size: field[1] as usize,
+            base_type: field[2],
+        });
+    }
+
+    let mut developer_data_size = 0;
+    if has_developer_data {
+        let developer_field_count = take(bytes, index, 1)?[0] as usize;
+        for _ in 0..developer_field_count {
+            // Field number, size, and developer data index
+            developer_data_size += take(bytes, index, 3)?[1] as usize;
+        }
+    }
+
+    Ok(MessageDefinition {
+        global_message_number,
+        is_big_endian,
+        fields,
+        developer_data_size,
+    })
+}
+
+fn decode_data(
+    definition: &MessageDefinition,
+    bytes: &[u8],
+    index: &mut usize,
+) -> Result<Vec<(u8, Option<f64>)>, FitError> {
+    let mut values = Vec::with_capacity(definition.fields.len());
+    for field in &definition.fields {
+        let field_bytes = take(bytes, index, field.size)?;
+        values.push((
+            field.number,
+            decode_value(field_bytes, field.base_type, definition.is_big_endian),
+        ));
+    }
+    take(bytes, index, definition.developer_data_size)?;
+    Ok(values)
+}
+
+fn values_to_record(values: &Vec<(u8, Option<f64>)>, timestamp: u32) -> FitRecord {
+    let mut record = FitRecord {
+        seconds_since_unix_epoch: timestamp + FIT_EPOCH_OFFSET,
+        power: None,
+        heart_rate: None,
+        cadence: None,
+        latitude: None,
+        longitude: None,
+        altitude: None,
+        distance: None,
+        speed: None,
+        left_right_balance: None,
+        accumulated_power: None,
+        total_cycles: None,
+    };
+
+    for (number, value) in values {
+        if let Some(v) = *value {
+            match number {
+                0 => record.latitude = Some(v * 45.0 / ((1 << 29) as f64)),
+                1 => record.longitude = Some(v * 45.0 / ((1 << 29) as f64)),
+                2 => record.altitude = Some((v / 5.0 - 500.0) as f32),
+                3 => record.heart_rate = Some(v as u8),
+                4 => record.cadence = Some(v as u8),
+                5 => record.distance = Some(v / 100.0),
+                6 => record.speed = Some((v / 1000.0) as f32),
+                7 => record.power = Some(v as u16),
+                19 => record.total_cycles = Some(v as u32),
+                29 => record.accumulated_power = Some(v as u32),
+                30 => record.left_right_balance = Some(v as u8),
+                // Other head units often only write the "enhanced" versions
+                73 if record.speed.is_none() => record.speed = Some((v / 1000.0) as f32),
+                78 if record.altitude.is_none() => record.altitude = Some((v / 5.0 - 500.0) as f32),
+                _ => (),
+            }
+        }
+    }
+
+    record
+}
+
+// A decoded data message of any type, with its timestamp resolved from
+// either its own timestamp field, a compressed header, or prior messages.
+struct FitMessage {
+    global_message_number: u16,
+    timestamp: Option<u32>,
+    values: Vec<(u8, Option<f64>)>,
+}
+
+impl FitMessage {
+    #[cfg(test)]
+    fn value(&self, number: u8) -> Option<f64> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == number)
+            .and_then(|(_, v)| *v)
+    }
+}
+
+fn decode_messages(bytes: &Vec<u8>) -> Result<Vec<FitMessage>, FitError> {
+    let header_length = *bytes.get(0).ok_or(FitError::Truncated)? as usize;
+    if header_length < 12 {
+        return Err(FitError::InvalidHeader);
+    }
+    if bytes.len() < header_length {
+        return Err(FitError::Truncated);
+    }
+    if &bytes[8..12] != b".FIT" {
+        return Err(FitError::InvalidHeader);
+    }
+
+    // The data size comes from the file, so a corrupt one mustn't overflow
+    let data_size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    let data_end = header_length
+        .checked_add(data_size)
+        .ok_or(FitError::Truncated)?;
+    let crc = data_end
+        .checked_add(2)
+        .and_then(|crc_end| bytes.get(data_end..crc_end))
+        .ok_or(FitError::Truncated)?;
+
+    // The 14 byte header has its own optional CRC, but the file CRC covers the
+    // header too, so we only check the latter.
+    let expected = u16::from_le_bytes([crc[0], crc[1]]);
+    let calculated = calculate_crc(&bytes[..data_end]);
+    if expected != calculated {
+        return Err(FitError::BadCrc {
+            expected,
+            calculated,
+        });
+    }
+
+    let data = &bytes[header_length..data_end];
+    let mut definitions: Vec<Option<MessageDefinition>> = (0..16).map(|_| None).collect();
+    let mut last_timestamp: Option<u32> = None;
+    let mut messages = Vec::new();
+    let mut index = 0;
+
+    while index < data.len() {
+        let header = data[index];
+        index += 1;
+
+        if header & 0x80 == 0x80 {
+            // Compressed timestamp header: only the lower 5 bits of the
+            // timestamp are sent, and they roll over relative to the last
+            // full timestamp.
+            let local = (header >> 5) & 0b11;
+            let offset = (header & 0x1f) as u32;
+            let definition = definitions[local as usize]
+                .as_ref()
+                .ok_or(FitError::UndefinedLocalMessage(local))?;
+            let values = decode_data(definition, data, &mut index)?;
+            last_timestamp = last_timestamp.map(|last| {
+                let t = (last & !0x1f) + offset;
+                if offset < last & 0x1f {
+                    t + 0x20
+                } else {
+                    t
+                }
+            });
+            messages.push(FitMessage {
+                global_message_number: definition.global_message_number,
+                timestamp: last_timestamp,
+                values,
+            });
+        } else if header & 0x40 == 0x40 {
+            let local = header & 0x0f;
+            let has_developer_data = header & 0x20 == 0x20;
+            definitions[local as usize] =
+                Some(decode_definition(data, &mut index, has_developer_data)?);
+        } else {
+            let local = header & 0x0f;
+            let definition = definitions[local as usize]
+                .as_ref()
+                .ok_or(FitError::UndefinedLocalMessage(local))?;
+            let values = decode_data(definition, data, &mut index)?;
+            let timestamp = values
+                .iter()
+                .find(|(number, _)| *number == 253)
+                .and_then(|(_, v)| v.map(|x| x as u32));
+            if let Some(_) = timestamp {
+                last_timestamp = timestamp;
+            }
+            messages.push(FitMessage {
+                global_message_number: definition.global_message_number,
+                timestamp: last_timestamp,
+                values,
+            });
+        }
+    }
+
+    Ok(messages)
+}
+
+// Decodes the record messages of a FIT file.  Any other message types are
+// validated and then skipped.  This handles everything that to_file writes,
+// as well as compressed timestamps and developer fields from other devices.
+#[allow(dead_code)]
+pub fn from_file(bytes: &Vec<u8>) -> Result<Vec<FitRecord>, FitError> {
+    Ok(decode_messages(bytes)?
+        .iter()
+        .filter(|m| m.global_message_number == 20)
+        // Records that come before any timestamp can't be placed, so we skip them
+        .filter_map(|m| m.timestamp.map(|ts| values_to_record(&m.values, ts)))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
+    use super::calculate_crc;
+    use super::decode_messages;
+    use super::from_file;
+    use super::to_activity_file;
     use super::to_file;
+    use super::to_workout_file;
+    use super::FitActivity;
+    use super::FitError;
+    use super::FitLap;
     use super::FitRecord;
+    use super::FitWorkoutStep;
+    use std::time::Duration;
 
     #[test]
     fn to_file_for_empty_vec() {
@@ -288,6 +1389,9 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -312,6 +1416,9 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    left_right_balance: None,
+                    accumulated_power: None,
+                    total_cycles: None,
                 },
                 FitRecord {
                     seconds_since_unix_epoch: 1583801577,
@@ -323,6 +1430,9 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    left_right_balance: None,
+                    accumulated_power: None,
+                    total_cycles: None,
                 }
             )),
         );
@@ -351,6 +1461,9 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    left_right_balance: None,
+                    accumulated_power: None,
+                    total_cycles: None,
                 },
                 FitRecord {
                     seconds_since_unix_epoch: 1583801577,
@@ -362,6 +1475,9 @@ mod tests {
                     altitude: Some(81.79999999999995),
                     distance: None,
                     speed: None,
+                    left_right_balance: None,
+                    accumulated_power: None,
+                    total_cycles: None,
                 }
             )),
         );
@@ -385,6 +1501,9 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -407,6 +1526,9 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -429,6 +1551,9 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -452,6 +1577,9 @@ mod tests {
                 altitude: Some(81.79999999999995),
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -476,6 +1604,9 @@ mod tests {
                 altitude: Some(81.79999999999995),
                 distance: None,
                 speed: None,
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
@@ -504,7 +1635,478 @@ mod tests {
                 altitude: None,
                 distance: Some(1000.0), // 1km
                 speed: Some(6.0),       // 21.6 km
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
             })),
         );
     }
+
+    fn record_without_gps(t: u32, power: u16, heart_rate: u8, cadence: u8) -> FitRecord {
+        FitRecord {
+            seconds_since_unix_epoch: t,
+            power: Some(power),
+            heart_rate: Some(heart_rate),
+            cadence: Some(cadence),
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: None,
+            speed: None,
+            left_right_balance: None,
+            accumulated_power: None,
+            total_cycles: None,
+        }
+    }
+
+    // Replaces the trailing CRC so that hand modified files are still valid
+    fn with_fixed_crc(mut bytes: Vec<u8>) -> Vec<u8> {
+        let len = bytes.len();
+        let crc = calculate_crc(&bytes[..len - 2]);
+        bytes[len - 2] = (crc & 0xff) as u8;
+        bytes[len - 1] = (crc >> 8) as u8;
+        bytes
+    }
+
+    #[test]
+    fn from_file_for_empty_vec() {
+        assert_eq!(Ok(Vec::new()), from_file(&to_file(&Vec::new())));
+    }
+
+    #[test]
+    fn from_file_round_trips_with_reused_and_separate_definitions() {
+        let records = vec![
+            record_without_gps(1583801576, 180, 120, 90),
+            record_without_gps(1583801577, 181, 121, 91),
+            FitRecord {
+                seconds_since_unix_epoch: 1583801578,
+                power: None,
+                heart_rate: Some(122),
+                cadence: None,
+                latitude: None,
+                longitude: None,
+                altitude: None,
+                distance: Some(1000.0),
+                speed: Some(6.0),
+                left_right_balance: None,
+                accumulated_power: None,
+                total_cycles: None,
+            },
+        ];
+        assert_eq!(Ok(records.clone()), from_file(&to_file(&records)));
+    }
+
+    #[test]
+    fn from_file_round_trips_lat_lon_alt() {
+        let decoded = from_file(&to_file(&vec![FitRecord {
+            seconds_since_unix_epoch: 1583801576,
+            power: None,
+            heart_rate: None,
+            cadence: None,
+            latitude: Some(45.48707197420299),
+            longitude: Some(-122.4767913389951),
+            altitude: Some(81.79999999999995),
+            distance: None,
+            speed: None,
+            left_right_balance: None,
+            accumulated_power: None,
+            total_cycles: None,
+        }]))
+        .unwrap();
+        assert_eq!(1, decoded.len());
+        assert_eq!(1583801576, decoded[0].seconds_since_unix_epoch);
+        // Semicircles and 0.2m steps are the precision limits of the format
+        assert!((decoded[0].latitude.unwrap() - 45.48707197420299).abs() < 1e-6);
+        assert!((decoded[0].longitude.unwrap() - -122.4767913389951).abs() < 1e-6);
+        assert!((decoded[0].altitude.unwrap() - 81.8).abs() < 0.2);
+    }
+
+    #[test]
+    fn from_file_rejects_bad_crc() {
+        let mut bytes = to_file(&vec![record_without_gps(1583801576, 180, 120, 90)]);
+        // Change the power without updating the CRC
+        bytes[32] = 0xb5;
+        assert_eq!(
+            Err(FitError::BadCrc {
+                expected: 0xc1e4,
+                calculated: 0xc319
+            }),
+            from_file(&bytes)
+        );
+    }
+
+    #[test]
+    fn from_file_rejects_truncated_file() {
+        let bytes = to_file(&vec![record_without_gps(1583801576, 180, 120, 90)]);
+        assert_eq!(
+            Err(FitError::Truncated),
+            from_file(&bytes[..bytes.len() - 3].to_vec())
+        );
+        assert_eq!(Err(FitError::Truncated), from_file(&bytes[..5].to_vec()));
+        assert_eq!(Err(FitError::Truncated), from_file(&Vec::new()));
+    }
+
+    #[test]
+    fn from_file_rejects_oversized_data_size() {
+        let mut bytes = to_file(&vec![record_without_gps(1583801576, 180, 120, 90)]);
+        // A data size that runs past the end of the file, or of memory
+        bytes[4..8].copy_from_slice(&u32::max_value().to_le_bytes());
+        assert_eq!(Err(FitError::Truncated), from_file(&bytes));
+    }
+
+    #[test]
+    fn from_file_rejects_message_that_overruns_data() {
+        let mut bytes = to_file(&vec![record_without_gps(1583801576, 180, 120, 90)]);
+        // Claim one byte less of data, so the record is cut short
+        bytes[4] -= 1;
+        bytes.remove(bytes.len() - 3);
+        assert_eq!(Err(FitError::Truncated), from_file(&with_fixed_crc(bytes)));
+    }
+
+    #[test]
+    fn from_file_rejects_unknown_base_type() {
+        let mut bytes = to_file(&vec![record_without_gps(1583801576, 180, 120, 90)]);
+        // The power field's base type
+        bytes[23] = 0x42;
+        assert_eq!(
+            Err(FitError::UnknownBaseType(0x42)),
+            from_file(&with_fixed_crc(bytes))
+        );
+    }
+
+    #[test]
+    fn from_file_rejects_invalid_header() {
+        let mut bytes = to_file(&Vec::new());
+        bytes[9] = b'X';
+        assert_eq!(Err(FitError::InvalidHeader), from_file(&bytes));
+    }
+
+    #[test]
+    fn from_file_handles_compressed_timestamps_and_invalid_values() {
+        let bytes = with_fixed_crc(vec![
+            0x0c, 0x20, 0xeb, 0x07, 0x1a, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, //
+            // Big endian definition of timestamp and power
+            0x40, 0x00, 0x01, 0x00, 0x14, 0x02, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, //
+            0x00, 0x38, 0xc9, 0x98, 0xe8, 0x00, 0xb4, // full timestamp
+            0x8a, 0x39, 0xc9, 0x98, 0xe8, 0xff, 0xff, // compressed, power invalid
+            0x00, 0x00, // crc
+        ]);
+        let mut expected = vec![
+            record_without_gps(1583801576, 180, 0, 0),
+            record_without_gps(1583801578, 0, 0, 0),
+        ];
+        for r in expected.iter_mut() {
+            r.heart_rate = None;
+            r.cadence = None;
+        }
+        expected[1].power = None;
+        assert_eq!(Ok(expected), from_file(&bytes));
+    }
+
+    #[test]
+    fn to_activity_file_for_empty_vec_only_has_file_id() {
+        let messages = decode_messages(&to_activity_file(&FitActivity {
+            records: Vec::new(),
+            laps: Vec::new(),
+            rr_intervals: Vec::new(),
+            pauses: Vec::new(),
+        }))
+        .unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!(0, messages[0].global_message_number);
+        // Type: activity
+        assert_eq!(Some(4.0), messages[0].value(0));
+    }
+
+    #[test]
+    fn to_activity_file_wraps_records_with_summaries() {
+        let mut records = vec![
+            record_without_gps(1583801576, 180, 120, 90),
+            record_without_gps(1583801577, 200, 124, 91),
+            record_without_gps(1583801586, 220, 125, 95),
+        ];
+        records[2].distance = Some(1000.0);
+        let bytes = to_activity_file(&FitActivity {
+            records: records.clone(),
+            laps: Vec::new(),
+            rr_intervals: Vec::new(),
+            pauses: Vec::new(),
+        });
+
+        assert_eq!(Ok(records), from_file(&bytes));
+
+        let messages = decode_messages(&bytes).unwrap();
+        assert_eq!(
+            vec![0, 23, 21, 20, 20, 20, 21, 19, 18, 34],
+            messages
+                .iter()
+                .map(|m| m.global_message_number)
+                .collect::<Vec<u16>>()
+        );
+
+        // Timer start and stop
+        assert_eq!(Some(0.0), messages[2].value(1));
+        assert_eq!(Some(4.0), messages[6].value(1));
+
+        let lap = &messages[7];
+        let session = &messages[8];
+        for (m, avg_speed_number) in vec![(lap, 13), (session, 14)] {
+            assert_eq!(Some(1583801586 - 631065600), m.timestamp);
+            assert_eq!(Some((1583801576 - 631065600) as f64), m.value(2));
+            assert_eq!(Some(10000.0), m.value(7));
+            assert_eq!(Some(10000.0), m.value(8));
+            assert_eq!(Some(100000.0), m.value(9));
+            assert_eq!(None, m.value(avg_speed_number));
+            assert_eq!(Some(123.0), m.value(avg_speed_number + 2));
+            assert_eq!(Some(125.0), m.value(avg_speed_number + 3));
+            assert_eq!(Some(92.0), m.value(avg_speed_number + 4));
+            assert_eq!(Some(95.0), m.value(avg_speed_number + 5));
+            assert_eq!(Some(200.0), m.value(avg_speed_number + 6));
+            assert_eq!(Some(220.0), m.value(avg_speed_number + 7));
+        }
+        assert_eq!(Some(1.0), session.value(26));
+
+        let activity = &messages[9];
+        assert_eq!(Some(10000.0), activity.value(0));
+        assert_eq!(Some(1.0), activity.value(1));
+    }
+
+    #[test]
+    fn to_activity_file_splits_laps_with_workout_steps() {
+        let records: Vec<FitRecord> = (0..6)
+            .map(|i| record_without_gps(1583801576 + i, 100 + 100 * (i as u16 / 3), 120, 90))
+            .collect();
+        let bytes = to_activity_file(&FitActivity {
+            records: records.clone(),
+            laps: vec![
+                // Starts after the first record, which is still included
+                FitLap {
+                    start: 1583801577,
+                    target_power: Some(100),
+                    planned_duration: Some(Duration::from_secs(2)),
+                },
+                // Never gets a record, so it is dropped
+                FitLap {
+                    start: 1583801579,
+                    target_power: Some(150),
+                    planned_duration: Some(Duration::from_secs(0)),
+                },
+                FitLap {
+                    start: 1583801579,
+                    target_power: Some(200),
+                    planned_duration: None,
+                },
+            ],
+            rr_intervals: Vec::new(),
+            pauses: Vec::new(),
+        });
+
+        assert_eq!(Ok(records), from_file(&bytes));
+
+        let messages = decode_messages(&bytes).unwrap();
+        let steps: Vec<_> = messages
+            .iter()
+            .filter(|m| m.global_message_number == 27)
+            .collect();
+        assert_eq!(2, steps.len());
+        assert_eq!(Some(1100.0), steps[0].value(5));
+        assert_eq!(Some(2000.0), steps[0].value(2));
+        assert_eq!(Some(1200.0), steps[1].value(6));
+        // Open duration
+        assert_eq!(Some(5.0), steps[1].value(1));
+
+        let laps: Vec<_> = messages
+            .iter()
+            .filter(|m| m.global_message_number == 19)
+            .collect();
+        assert_eq!(2, laps.len());
+        assert_eq!(Some(100.0), laps[0].value(19));
+        // Ends when the next lap starts, rather than at its own last record
+        assert_eq!(Some(3000.0), laps[0].value(7));
+        assert_eq!(Some(3000.0), laps[0].value(8));
+        assert_eq!(Some(0.0), laps[0].value(71));
+        assert_eq!(Some(200.0), laps[1].value(19));
+        assert_eq!(Some(1.0), laps[1].value(71));
+        assert_eq!(Some(1.0), laps[1].value(254));
+
+        let session = messages
+            .iter()
+            .find(|m| m.global_message_number == 18)
+            .unwrap();
+        assert_eq!(Some(2.0), session.value(26));
+        assert_eq!(Some(150.0), session.value(20));
+        // Lap times add up to the session's
+        for number in vec![7, 8] {
+            assert_eq!(
+                session.value(number),
+                Some(laps.iter().filter_map(|l| l.value(number)).sum())
+            );
+        }
+    }
+
+    #[test]
+    fn to_activity_file_stops_the_timer_while_paused() {
+        let records: Vec<FitRecord> = (0..10)
+            .map(|i| record_without_gps(1583801576 + i, 180, 120, 90))
+            .collect();
+        let bytes = to_activity_file(&FitActivity {
+            records: records.clone(),
+            laps: Vec::new(),
+            rr_intervals: Vec::new(),
+            pauses: vec![(1583801578, Some(1583801581)), (1583801584, None)],
+        });
+
+        assert_eq!(Ok(records), from_file(&bytes));
+
+        let messages = decode_messages(&bytes).unwrap();
+        let timer_events: Vec<(Option<u32>, Option<f64>)> = messages
+            .iter()
+            .filter(|m| m.global_message_number == 21)
+            .map(|m| (m.timestamp.map(|t| t + 631065600), m.value(1)))
+            .collect();
+        // Started, stopped, started, and then stopped for good
+        assert_eq!(
+            vec![
+                (Some(1583801576), Some(0.0)),
+                (Some(1583801578), Some(4.0)),
+                (Some(1583801581), Some(0.0)),
+                (Some(1583801584), Some(4.0)),
+            ],
+            timer_events
+        );
+
+        // 9s elapsed, of which 3s and then 1s were paused
+        for m in messages
+            .iter()
+            .filter(|m| m.global_message_number == 19 || m.global_message_number == 18)
+        {
+            assert_eq!(Some(9000.0), m.value(7));
+            assert_eq!(Some(5000.0), m.value(8));
+        }
+        let activity = messages
+            .iter()
+            .find(|m| m.global_message_number == 34)
+            .unwrap();
+        assert_eq!(Some(5000.0), activity.value(0));
+    }
+
+    #[test]
+    fn to_workout_file_with_repeat() {
+        let bytes = to_workout_file(
+            "Test",
+            &vec![
+                FitWorkoutStep::Power(100, Some(Duration::from_secs(300))),
+                FitWorkoutStep::Power(300, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(150, Some(Duration::from_secs(90))),
+                FitWorkoutStep::Repeat(1, 5),
+                FitWorkoutStep::Power(120, None),
+            ],
+        );
+
+        // A workout has no records, but it should still be a valid file
+        assert_eq!(Ok(Vec::new()), from_file(&bytes));
+
+        let messages = decode_messages(&bytes).unwrap();
+        assert_eq!(
+            vec![0, 26, 27, 27, 27, 27, 27],
+            messages
+                .iter()
+                .map(|m| m.global_message_number)
+                .collect::<Vec<u16>>()
+        );
+        // Type: workout
+        assert_eq!(Some(5.0), messages[0].value(0));
+        assert_eq!(Some(5.0), messages[1].value(6));
+
+        let repeat = &messages[5];
+        assert_eq!(Some(3.0), repeat.value(254));
+        assert_eq!(Some(6.0), repeat.value(1));
+        assert_eq!(Some(1.0), repeat.value(2));
+        assert_eq!(Some(5.0), repeat.value(4));
+
+        let interval = &messages[3];
+        assert_eq!(Some(60000.0), interval.value(2));
+        assert_eq!(Some(4.0), interval.value(3));
+        assert_eq!(Some(1300.0), interval.value(5));
+        assert_eq!(Some(1300.0), interval.value(6));
+    }
+
+    #[test]
+    fn to_activity_file_interleaves_hrv() {
+        let records = vec![
+            record_without_gps(1583801576, 180, 120, 90),
+            record_without_gps(1583801577, 180, 120, 90),
+        ];
+        let bytes = to_activity_file(&FitActivity {
+            records: records.clone(),
+            laps: Vec::new(),
+            pauses: Vec::new(),
+            rr_intervals: vec![
+                (1583801576, 0.5),
+                (1583801576, 0.501),
+                (1583801577, 0.502),
+                (1583801577, 0.503),
+                (1583801577, 0.504),
+                (1583801577, 0.505),
+                (1583801577, 0.506),
+                (1583801577, 0.507),
+                (1583801578, 0.508),
+            ],
+        });
+
+        assert_eq!(Ok(records), from_file(&bytes));
+
+        let messages = decode_messages(&bytes).unwrap();
+        let record_and_hrv: Vec<(u16, Option<f64>)> = messages
+            .iter()
+            .filter(|m| m.global_message_number == 20 || m.global_message_number == 78)
+            .map(|m| (m.global_message_number, m.value(0)))
+            .collect();
+        assert_eq!(
+            vec![
+                (78, Some(500.0)),
+                (20, None),
+                (78, Some(502.0)),
+                (78, Some(507.0)),
+                (20, None),
+                (78, Some(508.0)),
+            ],
+            record_and_hrv
+        );
+    }
+
+    #[test]
+    fn to_file_for_balance_accumulated_power_and_cycles() {
+        let record = FitRecord {
+            seconds_since_unix_epoch: 1583801576,
+            power: None,
+            heart_rate: None,
+            cadence: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: None,
+            speed: None,
+            left_right_balance: Some(0x80 | 52),
+            accumulated_power: Some(123456),
+            total_cycles: Some(4436),
+        };
+        assert_eq!(
+            vec!(
+                0x0c, 0x20, 0xeb, 0x07, 0x20, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
+                0x00, 0x14, 0x00, 0x04, 253, 0x04, 0x86, //
+                30, 0x01, 0x02, // balance def
+                29, 0x04, 0x86, // accumulated power def
+                19, 0x04, 0x86, // total cycles def
+                0,    // record type
+                0xe8, 0x98, 0xc9, 0x38, // time data
+                0xb4, // balance data
+                0x40, 0xe2, 0x01, 0x00, // accumulated power data
+                0x54, 0x11, 0x00, 0x00, // total cycles data
+                0xf1, 0x6c // crc
+            ),
+            to_file(&vec!(record.clone())),
+        );
+        assert_eq!(Ok(vec![record.clone()]), from_file(&to_file(&vec![record])));
+    }
 }
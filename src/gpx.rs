@@ -0,0 +1,202 @@
+use crate::display::STALE_AFTER;
+use crate::fit::FitRecord;
+use crate::utils::to_rfc3339;
+use std::fmt::Write;
+
+// Builds a GPX 1.1 track from the records that have a GPS fix.  Sensor data
+// doesn't necessarily arrive in the same second as a fix, so we carry the last
+// seen values forward onto each track point, until they'd be shown as stale.
+pub fn to_gpx(records: &Vec<FitRecord>) -> String {
+    let mut heart_rate = None;
+    let mut cadence = None;
+    let mut power = None;
+    let mut points = String::new();
+
+    for r in records {
+        let t = r.seconds_since_unix_epoch;
+        heart_rate = r.heart_rate.map(|x| (x, t)).or(heart_rate);
+        cadence = r.cadence.map(|x| (x, t)).or(cadence);
+        power = r.power.map(|x| (x, t)).or(power);
+
+        if let (Some(lat), Some(lon)) = (r.latitude, r.longitude) {
+            write!(
+                points,
+                "      <trkpt lat=\"{:.7}\" lon=\"{:.7}\">\n",
+                lat, lon
+            )
+            .unwrap();
+            if let Some(altitude) = r.altitude {
+                write!(points, "        <ele>{:.1}</ele>\n", altitude).unwrap();
+            }
+            write!(
+                points,
+                "        <time>{}</time>\n",
+                to_rfc3339(r.seconds_since_unix_epoch)
+            )
+            .unwrap();
+            points.push_str(&extensions_to_string(
+                fresh(heart_rate, t),
+                fresh(cadence, t),
+                fresh(power, t),
+            ));
+            points.push_str("      </trkpt>\n");
+        }
+    }
+
+    let name = records
+        .first()
+        .map(|r| to_rfc3339(r.seconds_since_unix_epoch))
+        .unwrap_or_default();
+
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<gpx version=\"1.1\" creator=\"rust-cycle\"",
+            " xmlns=\"http://www.topografix.com/GPX/1/1\"",
+            " xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v1\">\n",
+            "  <trk>\n",
+            "    <name>{}</name>\n",
+            "    <type>cycling</type>\n",
+            "    <trkseg>\n",
+            "{}",
+            "    </trkseg>\n",
+            "  </trk>\n",
+            "</gpx>\n",
+        ),
+        name, points
+    )
+}
+
+// A value last seen at seen, if it isn't stale by now
+fn fresh<T>(value: Option<(T, u32)>, now: u32) -> Option<T> {
+    value
+        .filter(|(_, seen)| (now.saturating_sub(*seen) as u64) <= STALE_AFTER.as_secs())
+        .map(|(x, _)| x)
+}
+
+// The TrackPointExtension schema has no power element, so we follow the
+// convention (Strava, GoldenCheetah, etc.) of a bare power element alongside it.
+fn extensions_to_string(heart_rate: Option<u8>, cadence: Option<u8>, power: Option<u16>) -> String {
+    if heart_rate.is_none() && cadence.is_none() && power.is_none() {
+        return String::new();
+    }
+
+    let mut s = String::from("        <extensions>\n");
+    if let Some(p) = power {
+        write!(s, "          <power>{}</power>\n", p).unwrap();
+    }
+    if heart_rate.is_some() || cadence.is_some() {
+        s.push_str("          <gpxtpx:TrackPointExtension>\n");
+        if let Some(hr) = heart_rate {
+            write!(s, "            <gpxtpx:hr>{}</gpxtpx:hr>\n", hr).unwrap();
+        }
+        if let Some(cad) = cadence {
+            write!(s, "            <gpxtpx:cad>{}</gpxtpx:cad>\n", cad).unwrap();
+        }
+        s.push_str("          </gpxtpx:TrackPointExtension>\n");
+    }
+    s.push_str("        </extensions>\n");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_gpx;
+    use crate::fit::FitRecord;
+
+    fn record(t: u32) -> FitRecord {
+        FitRecord {
+            seconds_since_unix_epoch: t,
+            power: None,
+            heart_rate: None,
+            cadence: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: None,
+            speed: None,
+            left_right_balance: None,
+            accumulated_power: None,
+            total_cycles: None,
+        }
+    }
+
+    #[test]
+    fn to_gpx_empty() {
+        assert_eq!(
+            to_gpx(&vec![]),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<gpx version=\"1.1\" creator=\"rust-cycle\"",
+                " xmlns=\"http://www.topografix.com/GPX/1/1\"",
+                " xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v1\">\n",
+                "  <trk>\n",
+                "    <name></name>\n",
+                "    <type>cycling</type>\n",
+                "    <trkseg>\n",
+                "    </trkseg>\n",
+                "  </trk>\n",
+                "</gpx>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn to_gpx_carries_sensor_data_onto_fixes() {
+        let mut a = record(1586000000);
+        a.heart_rate = Some(140);
+        a.power = Some(200);
+        let mut b = record(1586000001);
+        b.latitude = Some(37.7749);
+        b.longitude = Some(-122.4194);
+        b.altitude = Some(16.5);
+        b.cadence = Some(90);
+        let c = record(1586000002);
+
+        let gpx = to_gpx(&vec![a, b, c]);
+        assert!(gpx.contains("    <name>2020-04-04T11:33:20Z</name>\n"));
+        assert!(gpx.contains(concat!(
+            "    <trkseg>\n",
+            "      <trkpt lat=\"37.7749000\" lon=\"-122.4194000\">\n",
+            "        <ele>16.5</ele>\n",
+            "        <time>2020-04-04T11:33:21Z</time>\n",
+            "        <extensions>\n",
+            "          <power>200</power>\n",
+            "          <gpxtpx:TrackPointExtension>\n",
+            "            <gpxtpx:hr>140</gpxtpx:hr>\n",
+            "            <gpxtpx:cad>90</gpxtpx:cad>\n",
+            "          </gpxtpx:TrackPointExtension>\n",
+            "        </extensions>\n",
+            "      </trkpt>\n",
+            "    </trkseg>\n",
+        )));
+    }
+
+    #[test]
+    fn to_gpx_drops_stale_sensor_data() {
+        let mut a = record(1586000000);
+        a.heart_rate = Some(140);
+        a.power = Some(200);
+        let mut b = record(1586000004);
+        b.power = Some(210);
+        let mut c = record(1586000006);
+        c.latitude = Some(37.7749);
+        c.longitude = Some(-122.4194);
+        let mut d = record(1586000020);
+        d.latitude = Some(37.775);
+        d.longitude = Some(-122.4194);
+
+        let gpx = to_gpx(&vec![a, b, c, d]);
+        assert!(gpx.contains(concat!(
+            "        <time>2020-04-04T11:33:26Z</time>\n",
+            "        <extensions>\n",
+            "          <power>210</power>\n",
+            "        </extensions>\n",
+            "      </trkpt>\n",
+        )));
+        assert!(gpx.contains(concat!(
+            "        <time>2020-04-04T11:33:40Z</time>\n",
+            "      </trkpt>\n",
+        )));
+    }
+}
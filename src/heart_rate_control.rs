@@ -0,0 +1,171 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+// How often we reconsider the power target.  Heart rate responds over tens of
+// seconds, so there's no benefit to going faster than the HRM reports.
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Watts added per second, for each beat per minute we're below the target
+// (and likewise removed when above).
+const GAIN: f64 = 0.05;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HeartRateSettings {
+    pub target_bpm: u8,
+    // The trainer is never asked for anything outside of this range, and the
+    // minimum is where we start and where we fall back to without heart rate
+    pub min_power: u16,
+    pub max_power: u16,
+    // In Watts per second
+    pub max_change: f64,
+}
+
+// A slow integral controller:  the further we are from the target heart rate,
+// the faster power changes, up to a limit.
+pub struct HeartRateController {
+    settings: HeartRateSettings,
+    power: f64,
+}
+
+impl HeartRateController {
+    pub fn new(settings: HeartRateSettings) -> HeartRateController {
+        HeartRateController {
+            settings,
+            power: settings.min_power as f64,
+        }
+    }
+
+    // Takes the latest heart rate (None if it's stale or missing) and how long
+    // it's been since the last update, and gives the new power target.
+    pub fn update(&mut self, heart_rate: Option<u8>, elapsed: Duration) -> u16 {
+        let s = self.settings;
+        match heart_rate {
+            Some(bpm) => {
+                let max_change = s.max_change * elapsed.as_secs_f64();
+                let change = GAIN * (s.target_bpm as f64 - bpm as f64) * elapsed.as_secs_f64();
+                self.power = (self.power + change.max(-max_change).min(max_change))
+                    .max(s.min_power as f64)
+                    .min(s.max_power as f64);
+            }
+            // We can't tell how hard the rider is working, so we back off
+            // right away rather than gradually.
+            None => self.power = s.min_power as f64,
+        }
+        self.power.round() as u16
+    }
+}
+
+pub struct HeartRateHandle {
+    running: Arc<Mutex<bool>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl HeartRateHandle {
+    pub fn exit(&mut self) {
+        {
+            let mut running = self.running.lock().unwrap();
+            *running = false;
+        }
+        if let Some(jh) = mem::replace(&mut self.join_handle, None) {
+            jh.join().unwrap();
+        }
+    }
+}
+
+// Sets power from heart_rate (the latest bpm and when it was received) until
+// exit.  Readings older than stale_after are treated as missing.  set_power
+// returns whether the trainer applied the power, and if not, it's sent again
+// next time around.
+pub fn run<F>(
+    settings: HeartRateSettings,
+    heart_rate: Arc<Mutex<Option<(u8, Instant)>>>,
+    stale_after: Duration,
+    set_power: F,
+) -> HeartRateHandle
+where
+    F: Fn(u16) -> bool + Send + 'static,
+{
+    let running = Arc::new(Mutex::new(true));
+    let running_for_thread = running.clone();
+    let join_handle = Some(thread::spawn(move || {
+        let mut controller = HeartRateController::new(settings);
+        let mut last_power = None;
+        let mut last_update = Instant::now();
+        loop {
+            {
+                if !*running_for_thread.lock().unwrap() {
+                    break;
+                }
+            }
+            let bpm = heart_rate
+                .lock()
+                .unwrap()
+                .filter(|(_, received)| received.elapsed() <= stale_after)
+                .map(|(bpm, _)| bpm);
+            // A slow set_power (like a trainer that never acknowledges) would
+            // otherwise make the next step look much longer than it was
+            let elapsed = std::cmp::min(last_update.elapsed(), CONTROL_INTERVAL);
+            let power = controller.update(bpm, elapsed);
+            last_update = Instant::now();
+            if last_power != Some(power) && set_power(power) {
+                last_power = Some(power);
+            }
+            thread::sleep(CONTROL_INTERVAL);
+        }
+    }));
+
+    HeartRateHandle {
+        running,
+        join_handle,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeartRateController, HeartRateSettings};
+    use std::time::Duration;
+
+    const SETTINGS: HeartRateSettings = HeartRateSettings {
+        target_bpm: 140,
+        min_power: 100,
+        max_power: 200,
+        max_change: 1.0,
+    };
+
+    #[test]
+    fn update_raises_power_when_below_target() {
+        let mut controller = HeartRateController::new(SETTINGS);
+        // 10bpm low is half a Watt per second
+        assert_eq!(controller.update(Some(130), Duration::from_secs(10)), 105);
+        // Far below, we're limited by max_change
+        assert_eq!(controller.update(Some(90), Duration::from_secs(10)), 115);
+    }
+
+    #[test]
+    fn update_lowers_power_when_above_target() {
+        let mut controller = HeartRateController::new(SETTINGS);
+        controller.update(Some(100), Duration::from_secs(60));
+        assert_eq!(controller.update(Some(150), Duration::from_secs(20)), 150);
+    }
+
+    #[test]
+    fn update_stays_within_power_range() {
+        let mut controller = HeartRateController::new(SETTINGS);
+        assert_eq!(controller.update(Some(160), Duration::from_secs(10)), 100);
+        assert_eq!(controller.update(Some(60), Duration::from_secs(600)), 200);
+    }
+
+    #[test]
+    fn update_drops_to_min_power_without_heart_rate() {
+        let mut controller = HeartRateController::new(SETTINGS);
+        controller.update(Some(100), Duration::from_secs(60));
+        assert_eq!(controller.update(None, Duration::from_secs(1)), 100);
+        // And we climb back gradually
+        assert_eq!(controller.update(Some(100), Duration::from_secs(5)), 105);
+    }
+}
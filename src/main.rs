@@ -2,8 +2,11 @@ mod ble;
 mod buttons;
 mod cycle_tree;
 mod display;
+mod erg;
 mod fit;
 mod gps;
+mod gpx;
+mod heart_rate_control;
 mod inky_phat;
 #[cfg(feature = "simulator")]
 mod inky_phat_simulator;
@@ -11,10 +14,15 @@ mod memory_lcd;
 #[cfg(feature = "simulator")]
 mod memory_lcd_simulator;
 mod peripherals;
+mod tcx;
 mod telemetry_db;
+mod telemetry_export;
 mod telemetry_server;
 mod utils;
+mod virtual_gears;
 mod workout;
+mod workout_dsl;
+mod zwo;
 
 use ble::{
     csc_measurement,
@@ -23,22 +31,38 @@ use ble::{
         CscMeasurement,
     },
     cycling_power_measurement::{parse_cycling_power_measurement, CyclingPowerMeasurement},
+    fitness_machine,
+    fitness_machine::parse_indoor_bike_data,
     heart_rate_measurement::parse_hrm,
+    wahoo_trainer,
+    wahoo_trainer::SpindownStatus,
 };
 use btleplug::api::Central;
 use btleplug::bluez::manager::Manager;
-use peripherals::{cadence::Cadence, hrm, hrm::Hrm, kickr, kickr::Kickr, speed::Speed};
+use peripherals::{
+    cadence, hrm, kickr, kickr::Kickr, sensor::Sensor, speed, trainer, trainer::Trainer,
+};
 use std::collections::BTreeSet;
 use std::env;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use workout::{create_big_start_interval, ramp_test, single_value};
+use workout::{create_big_start_interval, ramp_test, single_value, Power};
 
 // TODO:  Allow calibration
 // In meters
 const WHEEL_CIRCUMFERENCE: f32 = 2.136;
 
+// In Watts, used to resolve percent of FTP workouts for riders that haven't
+// set their own FTP yet
+const DEFAULT_FTP: u16 = 250;
+
+// Where imported .zwo/.erg/.mrc and our own .workout files are read from
+const WORKOUT_DIRECTORY: &str = "workouts";
+
+// Spindowns take under a minute once the rider is up to speed
+const SPINDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[derive(Clone)]
 enum OrExit<T> {
     NotExit(T),
@@ -47,9 +71,50 @@ enum OrExit<T> {
 
 enum Location {
     Indoor(workout::Workout),
+    IndoorHeartRate(heart_rate_control::HeartRateSettings),
+    IndoorFreeRide(virtual_gears::Gearing),
+    Calibrate,
     Outdoor,
 }
 
+// What's in control of the trainer
+enum Ride {
+    Workout(Arc<Mutex<workout::WorkoutHandle>>),
+    HeartRate(heart_rate_control::HeartRateHandle),
+    FreeRide(
+        Arc<Mutex<virtual_gears::VirtualGears>>,
+        virtual_gears::VirtualGearsHandle,
+    ),
+}
+
+#[derive(Clone)]
+enum WorkoutChoice {
+    Named(&'static str),
+    Imported((String, workout::Workout)),
+}
+
+impl std::fmt::Display for WorkoutChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WorkoutChoice::Named(name) => write!(f, "{}", name),
+            WorkoutChoice::Imported((name, _)) => write!(f, "{}", name),
+        }
+    }
+}
+
+// The rider decides which FTP a workout is resolved against
+#[derive(Clone)]
+struct RiderChoice {
+    rider: &'static str,
+    choice: WorkoutChoice,
+}
+
+impl std::fmt::Display for RiderChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.choice)
+    }
+}
+
 impl<T: std::fmt::Display> std::fmt::Display for OrExit<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -79,39 +144,86 @@ pub fn main() {
         // Create our Buttons
         let mut buttons = buttons::Buttons::new();
 
-        // TODO: Select Enums
         use OrExit::{Exit, NotExit};
         use SelectionTree::{Leaf, Node};
+        use WorkoutChoice::{Imported, Named};
+        let imported = load_imported_workouts();
+        // Workouts that are relative to FTP are shared by every rider
+        let shared = |rider: &'static str| {
+            let leaf = |choice| Leaf(NotExit(RiderChoice { rider, choice }));
+            let library: Vec<SelectionTree<OrExit<RiderChoice>>> =
+                imported.iter().map(|x| leaf(Imported(x.clone()))).collect();
+            vec![
+                Node((
+                    "Fixed".to_string(),
+                    vec![
+                        leaf(Named("66%")),
+                        leaf(Named("68%")),
+                        leaf(Named("70%")),
+                        leaf(Named("72%")),
+                        leaf(Named("74%")),
+                    ],
+                )),
+                leaf(Named("Ramp")),
+                Node((
+                    "Heart Rate".to_string(),
+                    vec![
+                        leaf(Named("HR 120")),
+                        leaf(Named("HR 130")),
+                        leaf(Named("HR 140")),
+                    ],
+                )),
+                Node((
+                    "Free Ride".to_string(),
+                    vec![leaf(Named("Grade")), leaf(Named("Resistance"))],
+                )),
+            ]
+            .into_iter()
+            // An empty menu can't be selected from
+            .chain(if library.is_empty() {
+                None
+            } else {
+                Some(Node(("Library".to_string(), paged(library))))
+            })
+        };
+        let named = |rider, name| {
+            Leaf(NotExit(RiderChoice {
+                rider,
+                choice: Named(name),
+            }))
+        };
         let workout_name = selection_tree(
             &mut display,
             &mut buttons,
             vec![
-                Node(("Zenia".to_string(), vec![Leaf(NotExit("100W"))])),
+                Node((
+                    "Zenia".to_string(),
+                    paged(
+                        vec![named("Zenia", "100W")]
+                            .into_iter()
+                            .chain(shared("Zenia"))
+                            .collect(),
+                    ),
+                )),
                 Node((
                     "Nathan".to_string(),
-                    vec![
-                        Leaf(NotExit("Outdoor")),
-                        Node((
-                            "Fixed".to_string(),
-                            vec![
-                                Leaf(NotExit("165W")),
-                                Leaf(NotExit("170W")),
-                                Leaf(NotExit("175W")),
-                                Leaf(NotExit("180W")),
-                                Leaf(NotExit("185W")),
-                            ],
-                        )),
-                        Leaf(NotExit("Ramp")),
-                        Leaf(NotExit("1st Big Interval")),
-                    ],
+                    paged(
+                        vec![named("Nathan", "Outdoor")]
+                            .into_iter()
+                            .chain(shared("Nathan"))
+                            .chain(vec![named("Nathan", "1st Big Interval")])
+                            .collect(),
+                    ),
                 )),
+                // These are all absolute, so the rider doesn't matter
                 Node((
                     "Tests".to_string(),
                     vec![
-                        Leaf(NotExit("GPS Only")),
-                        Leaf(NotExit("GPS & HR")),
-                        Leaf(NotExit("P/H/70W")),
-                        Leaf(NotExit("P/H/Ramp")),
+                        named("Nathan", "GPS Only"),
+                        named("Nathan", "GPS & HR"),
+                        named("Nathan", "P/H/70W"),
+                        named("Nathan", "P/H/Ramp"),
+                        named("Nathan", "Calibrate"),
                     ],
                 )),
                 Leaf(Exit),
@@ -136,32 +248,87 @@ pub fn main() {
             NotExit(x) => x,
         };
 
-        let (use_hr, use_cadence, location) = match workout_name {
-            "100W" => (false, false, Location::Indoor(single_value(100))),
-            "Outdoor" => (true, true, Location::Outdoor),
-            "165W" => (true, true, Location::Indoor(single_value(165))),
-            "170W" => (true, true, Location::Indoor(single_value(170))),
-            "175W" => (true, true, Location::Indoor(single_value(175))),
-            "180W" => (true, true, Location::Indoor(single_value(180))),
-            "185W" => (true, true, Location::Indoor(single_value(185))),
-            "Ramp" => (true, true, Location::Indoor(ramp_test(120))),
-            "1st Big Interval" => (
+        // Percent of FTP targets are resolved when the workout starts
+        let ftp = db
+            .get_profile(workout_name.rider)
+            .unwrap()
+            .map_or(DEFAULT_FTP, |p| p.ftp);
+
+        let fixed = |percent| {
+            (
                 true,
                 true,
-                Location::Indoor(create_big_start_interval(
-                    (Duration::from_secs(300), 140),
-                    14,
-                    Duration::from_secs(150),
-                    (Duration::from_secs(60), 320),
-                    (Duration::from_secs(90), 120),
-                    Some(160),
-                )),
-            ),
-            "P/H/70W" => (true, false, Location::Indoor(single_value(70))),
-            "P/H/Ramp" => (true, false, Location::Indoor(ramp_test(90))),
-            "GPS Only" => (false, false, Location::Outdoor),
-            "GPS & HR" => (true, false, Location::Outdoor),
-            _ => panic!("Unexpected workout_name!"),
+                Location::Indoor(single_value(Power::FtpPercent(percent))),
+            )
+        };
+        // Aerobic rides, so the trainer stays between recovery and tempo
+        let heart_rate = |target_bpm| {
+            (
+                true,
+                true,
+                Location::IndoorHeartRate(heart_rate_control::HeartRateSettings {
+                    target_bpm,
+                    min_power: Power::FtpPercent(40.0).resolve(ftp),
+                    max_power: Power::FtpPercent(80.0).resolve(ftp),
+                    max_change: 0.5,
+                }),
+            )
+        };
+        let (use_hr, use_cadence, location) = match workout_name.choice.clone() {
+            Imported((_, workout)) => (true, true, Location::Indoor(workout)),
+            Named(name) => match name {
+                "100W" => (
+                    false,
+                    false,
+                    Location::Indoor(single_value(Power::Watts(100))),
+                ),
+                "Outdoor" => (true, true, Location::Outdoor),
+                "66%" => fixed(66.0),
+                "68%" => fixed(68.0),
+                "70%" => fixed(70.0),
+                "72%" => fixed(72.0),
+                "74%" => fixed(74.0),
+                "Ramp" => (
+                    true,
+                    true,
+                    Location::Indoor(ramp_test(Power::FtpPercent(48.0))),
+                ),
+                "HR 120" => heart_rate(120),
+                "HR 130" => heart_rate(130),
+                "HR 140" => heart_rate(140),
+                "Grade" => (
+                    true,
+                    true,
+                    Location::IndoorFreeRide(virtual_gears::Gearing::Grade),
+                ),
+                "Resistance" => (
+                    true,
+                    true,
+                    Location::IndoorFreeRide(virtual_gears::Gearing::Resistance),
+                ),
+                "1st Big Interval" => (
+                    true,
+                    true,
+                    Location::Indoor(create_big_start_interval(
+                        (Duration::from_secs(300), Power::FtpPercent(56.0)),
+                        14,
+                        Duration::from_secs(150),
+                        (Duration::from_secs(60), Power::FtpPercent(128.0)),
+                        (Duration::from_secs(90), Power::FtpPercent(48.0)),
+                        Some(Power::FtpPercent(64.0)),
+                    )),
+                ),
+                "P/H/70W" => (
+                    true,
+                    false,
+                    Location::Indoor(single_value(Power::Watts(70))),
+                ),
+                "P/H/Ramp" => (true, false, Location::Indoor(ramp_test(Power::Watts(90)))),
+                "GPS Only" => (false, false, Location::Outdoor),
+                "GPS & HR" => (true, false, Location::Outdoor),
+                "Calibrate" => (false, false, Location::Calibrate),
+                _ => panic!("Unexpected workout_name!"),
+            },
         };
 
         // We want instant, because we want this to be monotonic. We don't want
@@ -219,12 +386,21 @@ pub fn main() {
         );
         lock_and_show(&display_mutex, &"Connecting to Devices.");
 
+        // Sensors the rider has paired are used instead of the first we find
+        let paired = |role| db.get_pairing(workout_name.rider, role).unwrap();
+
         // We need to bind to keep our speed peripheral until the end of the scope
         let _speed = if let Location::Outdoor = location {
             // Connect to Speed meter and print its raw notifications
             let speed_measure = or_crash_with_msg(
                 &display_mutex,
-                Speed::new(central.clone()).ok().and_then(|x| x),
+                Sensor::connect(
+                    central.clone(),
+                    &speed::KIND,
+                    paired(speed::KIND.role).as_deref(),
+                )
+                .ok()
+                .and_then(|x| x),
                 "Could not connect to Speed Measure!",
             );
 
@@ -259,20 +435,32 @@ pub fn main() {
             None
         };
 
+        // The latest heart rate and when it arrived, for heart rate control
+        let latest_heart_rate = Arc::new(Mutex::new(None));
+
         // We need to bind to keep our hrm until the end of the scope
         let _hrm = if use_hr {
             // Connect to HRM and print its parsed notifications
             let hrm = or_crash_with_msg(
                 &display_mutex,
-                Hrm::new(central.clone()).ok().and_then(|x| x),
+                Sensor::connect(
+                    central.clone(),
+                    &hrm::KIND,
+                    paired(hrm::KIND.role).as_deref(),
+                )
+                .ok()
+                .and_then(|x| x),
                 "Could not connect to heart rate monitor!",
             );
 
             let db_hrm = db.clone();
             let display_mutex_hrm = display_mutex.clone();
+            let latest_heart_rate_hrm = latest_heart_rate.clone();
             hrm.on_notification(Box::new(move |n| {
+                let bpm = parse_hrm(&n.value).bpm as u8;
+                *latest_heart_rate_hrm.lock().unwrap() = Some((bpm, Instant::now()));
                 let mut display = display_mutex_hrm.lock().unwrap();
-                display.update_heart_rate(Some(parse_hrm(&n.value).bpm as u8));
+                display.update_heart_rate(Some(bpm));
                 let elapsed = start.elapsed();
                 db_hrm
                     .insert(
@@ -288,21 +476,80 @@ pub fn main() {
             None
         };
 
-        let kickr_and_handle = if let Location::Indoor(workout) = location {
-            // Connect to Kickr and print its raw notifications
-            let kickr = or_crash_with_msg(
+        let trainer_and_ride = if let Location::Outdoor = location {
+            None
+        } else {
+            // Calibration is all we do, then we exit (and systemd restarts us).
+            // Only a Kickr can be calibrated.
+            if let Location::Calibrate = location {
+                let kickr = or_crash_with_msg(
+                    &display_mutex,
+                    Kickr::new(central.clone(), paired(trainer::ROLE).as_deref())
+                        .ok()
+                        .and_then(|x| x),
+                    "Could not connect to a Kickr!",
+                );
+                // The last result is shown first, so there's something to
+                // compare against
+                if let Some(calibration) = db.get_calibration(&kickr.address()).unwrap() {
+                    lock_and_show(
+                        &display_mutex,
+                        &format!("Last spindown {}ms", calibration.spindown.as_millis()),
+                    );
+                    thread::sleep(Duration::from_secs(3));
+                }
+                lock_and_show(&display_mutex, &"Starting spindown");
+                let spindown = kickr
+                    .spindown(SPINDOWN_TIMEOUT, |status| {
+                        lock_and_show(
+                            &display_mutex,
+                            match status {
+                                SpindownStatus::SpeedUp => "Pedal up to speed",
+                                SpindownStatus::Coast => "Stop pedaling",
+                                SpindownStatus::Complete(_) => "Spindown complete",
+                                SpindownStatus::Failed => "Spindown failed!",
+                            },
+                        )
+                    })
+                    .unwrap_or_else(|e| {
+                        println!("Spindown failed: {}", e);
+                        lock_and_show(&display_mutex, &"Spindown failed!");
+                        None
+                    });
+                if let Some(spindown) = spindown {
+                    db.set_calibration(
+                        &kickr.address(),
+                        &telemetry_db::Calibration {
+                            spindown,
+                            seconds_since_unix_epoch: session_key,
+                        },
+                    )
+                    .unwrap();
+                    lock_and_show(
+                        &display_mutex,
+                        &format!("Spindown {}ms", spindown.as_millis()),
+                    );
+                }
+                thread::sleep(Duration::from_secs(5));
+                return;
+            }
+
+            // Connect to our trainer and print its raw notifications
+            let trainer = or_crash_with_msg(
                 &display_mutex,
-                Kickr::new(central.clone()).ok().and_then(|x| x),
-                "Could not connect to kickr!",
+                trainer::connect(central.clone(), paired(trainer::ROLE).as_deref())
+                    .ok()
+                    .and_then(|x| x),
+                "Could not connect to a trainer!",
             );
 
-            let db_kickr = db.clone();
-            let display_mutex_kickr = display_mutex.clone();
+            let db_trainer = db.clone();
+            let display_mutex_trainer = display_mutex.clone();
             let mut o_last_power_reading: Option<CyclingPowerMeasurement> = None;
             let mut acc_torque = 0.0;
-            kickr.on_notification(Box::new(move |n| {
+            trainer.on_notification(Box::new(move |n| {
                 if n.uuid == kickr::MEASURE_UUID {
-                    let mut display = display_mutex_kickr.lock().unwrap();
+                    let mut display = display_mutex_trainer.lock().unwrap();
                     let power_reading = parse_cycling_power_measurement(&n.value);
                     let o_new_acc_torque = o_last_power_reading
                         .as_ref()
@@ -314,7 +561,21 @@ pub fn main() {
                     display.update_power(Some(power_reading.instantaneous_power));
                     o_last_power_reading = Some(power_reading);
                     let elapsed = start.elapsed();
-                    db_kickr
+                    db_trainer
+                        .insert(
+                            session_key,
+                            elapsed,
+                            telemetry_db::Notification::Ble((n.uuid, n.value)),
+                        )
+                        .unwrap();
+                } else if n.uuid == fitness_machine::INDOOR_BIKE_DATA_UUID {
+                    let data = parse_indoor_bike_data(&n.value);
+                    if let Some(power) = data.instantaneous_power {
+                        let mut display = display_mutex_trainer.lock().unwrap();
+                        display.update_power(Some(power));
+                    }
+                    let elapsed = start.elapsed();
+                    db_trainer
                         .insert(
                             session_key,
                             elapsed,
@@ -322,28 +583,90 @@ pub fn main() {
                         )
                         .unwrap();
                 } else {
-                    println!("Non-power notification from kickr: {:?}", n);
+                    let response = if n.uuid == fitness_machine::CONTROL_POINT_UUID {
+                        fitness_machine::parse_response(&n.value)
+                    } else {
+                        wahoo_trainer::parse_response(&n.value)
+                    };
+                    match response {
+                        Some(r) if !r.success => {
+                            println!("Trainer rejected command {:#04x}", r.opcode)
+                        }
+                        Some(_) => (),
+                        None => println!("Non-power notification from trainer: {:?}", n),
+                    }
                 }
             }));
 
             // run our workout
             // Our workout will drop the closure after the workout ends (last
-            // power_set) and if we don't hold a reference to our kickr, it will
-            // be dropped along with the closure.  Dropping the kickr ends all
-            // of its subscriptions.
+            // power_set) and if we don't hold a reference to our trainer, it
+            // will be dropped along with the closure.  Dropping the trainer ends
+            // all of its subscriptions.
             // TODO: Maybe all workouts should have an explicit end, rather than
             // a tail?  That would make this more intuitive.  Then at the end of
             // the workout, the program exits (and systemd restarts it).
-            let kickr = Arc::new(kickr);
-            let kickr_for_workout = kickr.clone();
-            let workout_handle = workout.run(Instant::now(), move |p| {
-                kickr_for_workout.set_power(p).unwrap();
-            });
+            let trainer: Arc<dyn Trainer> = Arc::from(trainer);
+            let trainer_for_workout = trainer.clone();
+            // Failures are reported, and whatever's in control of the trainer
+            // decides whether to try again
+            let set_power = move |p| match trainer_for_workout.set_power(p) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Could not set power to {}W: {}", p, e);
+                    false
+                }
+            };
+            let ride = match location {
+                Location::Indoor(workout) => {
+                    let db_workout = db.clone();
+                    Ride::Workout(Arc::new(Mutex::new(workout.run(
+                        ftp,
+                        Instant::now(),
+                        set_power,
+                        move |i, p, d| {
+                            db_workout
+                                .insert(
+                                    session_key,
+                                    start.elapsed(),
+                                    telemetry_db::Notification::WorkoutStep((i as u32, p, d)),
+                                )
+                                .unwrap();
+                        },
+                    ))))
+                }
+                Location::IndoorHeartRate(settings) => Ride::HeartRate(heart_rate_control::run(
+                    settings,
+                    latest_heart_rate.clone(),
+                    display::STALE_AFTER,
+                    set_power,
+                )),
+                Location::IndoorFreeRide(gearing) => {
+                    let gears = Arc::new(Mutex::new(virtual_gears::VirtualGears::new(gearing)));
+                    let trainer_for_gears = trainer.clone();
+                    let gh = virtual_gears::run(gears.clone(), move |level| {
+                        let result = match gearing {
+                            virtual_gears::Gearing::Grade => trainer_for_gears.set_grade(level),
+                            virtual_gears::Gearing::Resistance => {
+                                trainer_for_gears.set_resistance(level)
+                            }
+                        };
+                        match result {
+                            Ok(()) => true,
+                            Err(e) => {
+                                println!("Could not change gear to {}%: {}", level, e);
+                                false
+                            }
+                        }
+                    });
+                    Ride::FreeRide(gears, gh)
+                }
+                Location::Calibrate => panic!("Calibration isn't a ride!"),
+                Location::Outdoor => panic!("Outdoor rides have no trainer!"),
+            };
 
-            lock_and_show(&display_mutex, &"Setup Complete for Kickr");
-            Some((workout_handle, kickr))
-        } else {
-            None
+            lock_and_show(&display_mutex, &"Setup Complete for Trainer");
+            Some((ride, trainer))
         };
 
         // We need to bind to keep our cadence peripheral until the end of the scope
@@ -351,7 +674,13 @@ pub fn main() {
             // Connect to Cadence meter and print its raw notifications
             let cadence_measure = or_crash_with_msg(
                 &display_mutex,
-                Cadence::new(central.clone()).ok().and_then(|x| x),
+                Sensor::connect(
+                    central.clone(),
+                    &cadence::KIND,
+                    paired(cadence::KIND.role).as_deref(),
+                )
+                .ok()
+                .and_then(|x| x),
                 "Could not connect to Cadence Measure!",
             );
 
@@ -397,26 +726,99 @@ pub fn main() {
             }),
         );
 
+        // While riding indoors, B pauses or resumes, C skips to the next step,
+        // and D adds a minute to the current one
+        if let Some((Ride::Workout(wh), _)) = &trainer_and_ride {
+            let wh_for_pause = wh.clone();
+            let db_pause = db.clone();
+            buttons.on_press(
+                buttons::Button::ButtonB,
+                Box::new(move || {
+                    let wh = wh_for_pause.lock().unwrap();
+                    let is_paused = !wh.is_paused();
+                    if is_paused {
+                        wh.pause();
+                    } else {
+                        wh.resume();
+                    }
+                    // Exports stop their timers while we're paused
+                    db_pause
+                        .insert(
+                            session_key,
+                            start.elapsed(),
+                            telemetry_db::Notification::Paused(is_paused),
+                        )
+                        .unwrap();
+                }),
+            );
+            let wh_for_skip = wh.clone();
+            buttons.on_press(
+                buttons::Button::ButtonC,
+                Box::new(move || wh_for_skip.lock().unwrap().skip()),
+            );
+            let wh_for_extend = wh.clone();
+            buttons.on_press(
+                buttons::Button::ButtonD,
+                Box::new(move || wh_for_extend.lock().unwrap().extend()),
+            );
+        }
+
+        // While free riding, C shifts to an easier gear and D to a harder one
+        if let Some((Ride::FreeRide(gears, _), _)) = &trainer_and_ride {
+            let gears_for_down = gears.clone();
+            buttons.on_press(
+                buttons::Button::ButtonC,
+                Box::new(move || gears_for_down.lock().unwrap().shift_down()),
+            );
+            let gears_for_up = gears.clone();
+            buttons.on_press(
+                buttons::Button::ButtonD,
+                Box::new(move || gears_for_up.lock().unwrap().shift_up()),
+            );
+        }
+
         // Update it every second
         let display_mutex_for_render = display_mutex.clone();
         let m_will_exit_for_render = m_will_exit.clone();
+        let wh_for_render = match &trainer_and_ride {
+            Some((Ride::Workout(wh), _)) => Some(wh.clone()),
+            _ => None,
+        };
+        let gears_for_render = match &trainer_and_ride {
+            Some((Ride::FreeRide(gears, _), _)) => Some(gears.clone()),
+            _ => None,
+        };
         let render_handle = thread::spawn(move || loop {
             {
                 if *m_will_exit_for_render.lock().unwrap() {
                     break;
                 }
             };
+            // Taken before locking the display, so we never hold both locks
+            let progress = wh_for_render
+                .as_ref()
+                .and_then(|wh| wh.lock().unwrap().progress());
+            let gear = gears_for_render.as_ref().map(|gears| {
+                let gears = gears.lock().unwrap();
+                (gears.gear(), gears.gear_count())
+            });
             {
                 let mut display = display_mutex_for_render.lock().unwrap();
+                display.update_workout_progress(progress);
+                display.update_gear(gear);
                 display.render();
             }
             thread::sleep(Duration::from_millis(100));
         });
 
-        if let Some((mut wh, _)) = kickr_and_handle {
-            wh.exit();
-        }
+        // Rendering stops once A is held, and only then is the ride over
         render_handle.join().unwrap();
+        match trainer_and_ride {
+            Some((Ride::Workout(wh), _)) => wh.lock().unwrap().exit(),
+            Some((Ride::HeartRate(mut hh), _)) => hh.exit(),
+            Some((Ride::FreeRide(_, mut gh), _)) => gh.exit(),
+            None => (),
+        }
         lock_and_show(&display_mutex, &"Goodbye");
     }
 }
@@ -454,6 +856,79 @@ fn selection_tree<O: std::fmt::Display + Clone>(
     }
 }
 
+// Our buttons only allow for five options at a time, so longer lists are split
+// into pages, where the last option of each page leads to the next.
+fn paged<T>(mut options: Vec<SelectionTree<T>>) -> Vec<SelectionTree<T>> {
+    if options.len() > 5 {
+        let rest = options.split_off(4);
+        options.push(SelectionTree::Node(("More...".to_string(), paged(rest))));
+    }
+    options
+}
+
+// Reads every workout file in our workout directory (sorted by file name),
+// skipping any that can't be read or parsed.
+fn load_imported_workouts() -> Vec<(String, workout::Workout)> {
+    let mut paths: Vec<std::path::PathBuf> = match std::fs::read_dir(WORKOUT_DIRECTORY) {
+        Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+        Err(_) => vec![],
+    };
+    paths.sort();
+
+    let mut workouts = Vec::new();
+    for path in paths {
+        match load_workout(&path) {
+            Some(Ok(workout)) => workouts.push(workout),
+            Some(Err(e)) => println!("Could not load {}: {}", path.display(), e),
+            None => (),
+        }
+    }
+    workouts
+}
+
+// Finds the workout in our workout directory with this file name (ignoring its
+// extension), or None if there isn't one.
+fn load_library_workout(file_stem: &str) -> Option<Result<(String, workout::Workout), String>> {
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(WORKOUT_DIRECTORY)
+        .ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.file_stem().map_or(false, |s| s == file_stem))
+        .collect();
+    paths.sort();
+    paths.iter().find_map(|p| load_workout(p))
+}
+
+// Returns None for files that aren't workouts.  Workouts without a name are
+// named after their file.
+fn load_workout(path: &std::path::Path) -> Option<Result<(String, workout::Workout), String>> {
+    let parse: fn(&str) -> Result<(String, workout::Workout), String> =
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("zwo") => |s| zwo::parse_zwo(s).map_err(|e| e.to_string()),
+            // Our own format has no name
+            Some("workout") => |s| {
+                workout_dsl::parse_workout(s)
+                    .map(|(ct, tail)| (String::new(), workout::Workout::new(ct, tail)))
+                    .map_err(|e| e.to_string())
+            },
+            // .mrc files are the same format, just in percent of FTP
+            Some("erg") | Some("mrc") => |s| erg::parse_erg(s).map_err(|e| e.to_string()),
+            _ => return None,
+        };
+    Some(
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse(&s))
+            .map(|(name, workout)| {
+                let name = if name.is_empty() {
+                    path.file_stem().unwrap().to_string_lossy().to_string()
+                } else {
+                    name
+                };
+                (name, workout)
+            }),
+    )
+}
+
 fn selection<O: std::fmt::Display + Clone>(
     display: &mut display::Display,
     buttons: &mut buttons::Buttons,
@@ -562,12 +1037,58 @@ fn or_crash_with_msg<T>(
 }
 
 fn db_session_to_fit(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8> {
+    fit::to_activity_file(&db_session_to_activity(db, session_key))
+}
+
+fn db_session_to_gpx(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8> {
+    gpx::to_gpx(&db_session_to_activity(db, session_key).records).into_bytes()
+}
+
+fn db_session_to_tcx(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8> {
+    tcx::to_tcx(&db_session_to_activity(db, session_key)).into_bytes()
+}
+
+// Every notification is kept, so these are streamed straight from the DB
+fn db_session_to_csv<'a>(
+    db: &'a telemetry_db::TelemetryDb,
+    session_key: u64,
+) -> impl std::io::Read + 'a {
+    let rows = db
+        .get_session_entries(session_key)
+        .filter_map(|x| x.ok())
+        .map(|(d, value)| {
+            telemetry_export::row_to_csv(&telemetry_export::notification_to_row(d, &value))
+        });
+    telemetry_export::RowReader::new(std::iter::once(telemetry_export::csv_header()).chain(rows))
+}
+
+fn db_session_to_ndjson<'a>(
+    db: &'a telemetry_db::TelemetryDb,
+    session_key: u64,
+) -> impl std::io::Read + 'a {
+    telemetry_export::RowReader::new(
+        db.get_session_entries(session_key)
+            .filter_map(|x| x.ok())
+            .map(|(d, value)| {
+                telemetry_export::row_to_ndjson(&telemetry_export::notification_to_row(d, &value))
+            }),
+    )
+}
+
+fn db_session_to_activity(db: &telemetry_db::TelemetryDb, session_key: u64) -> fit::FitActivity {
     let mut last_power: Option<u16> = None;
     let mut last_cadence_csc_measurement: Option<CscMeasurement> = None;
     let mut last_wheel_csc_measurement: Option<CscMeasurement> = None;
+    let mut last_power_measurement: Option<(CyclingPowerMeasurement, Duration)> = None;
+    let mut last_ftms_elapsed: Option<Duration> = None;
     let mut wheel_count = 0;
+    let mut crank_count = 0;
+    let mut accumulated_energy = 0.0;
     let mut record: Option<fit::FitRecord> = None;
     let mut records = Vec::new();
+    let mut laps = Vec::new();
+    let mut rr_intervals = Vec::new();
+    let mut pauses: Vec<(u32, Option<u32>)> = Vec::new();
     let empty_record = |t| fit::FitRecord {
         seconds_since_unix_epoch: t,
         power: None,
@@ -578,6 +1099,9 @@ fn db_session_to_fit(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8
         altitude: None,
         distance: None,
         speed: None,
+        left_right_balance: None,
+        accumulated_power: None,
+        total_cycles: None,
     };
 
     for x in db.get_session_entries(session_key) {
@@ -606,14 +1130,107 @@ fn db_session_to_fit(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8
                     r
                 }
                 telemetry_db::Notification::Gps(_) => r,
+                telemetry_db::Notification::WorkoutStep((_, p, d)) => {
+                    // Each step of the workout becomes its own lap
+                    laps.push(fit::FitLap {
+                        start: seconds_since_unix_epoch,
+                        target_power: Some(p),
+                        planned_duration: d,
+                    });
+                    r
+                }
+                telemetry_db::Notification::Paused(true) => {
+                    pauses.push((seconds_since_unix_epoch, None));
+                    r
+                }
+                telemetry_db::Notification::Paused(false) => {
+                    if let Some((_, resumed @ None)) = pauses.last_mut() {
+                        *resumed = Some(seconds_since_unix_epoch);
+                    }
+                    r
+                }
                 telemetry_db::Notification::Ble((hrm::MEASURE_UUID, v)) => {
-                    r.heart_rate = Some(parse_hrm(&v).bpm as u8);
+                    let measurement = parse_hrm(&v);
+                    r.heart_rate = Some(measurement.bpm as u8);
+                    for rr in measurement.rr_intervals {
+                        rr_intervals.push((seconds_since_unix_epoch, rr));
+                    }
                     r
                 }
                 telemetry_db::Notification::Ble((kickr::MEASURE_UUID, v)) => {
-                    let p = parse_cycling_power_measurement(&v).instantaneous_power as u16;
+                    let measurement = parse_cycling_power_measurement(&v);
+                    let p = measurement.instantaneous_power as u16;
                     last_power = Some(p);
                     r.power = Some(p);
+                    // FIT balance is flagged with 0x80 when it's the right
+                    // pedal's share, otherwise we don't know which pedal it is
+                    r.left_right_balance = measurement.pedal_power_balance_percent.map(|x| {
+                        if measurement.is_pedal_power_balance_left {
+                            0x80 | (100.0 - x).round() as u8
+                        } else {
+                            x.round() as u8
+                        }
+                    });
+                    if let Some((last, last_d)) = &last_power_measurement {
+                        // Torque gives us the energy directly, otherwise we
+                        // assume the last power was held until now.
+                        accumulated_energy += last
+                            .new_accumulated_torque(&measurement)
+                            .map(|t| 2.0 * std::f64::consts::PI * t)
+                            .unwrap_or_else(|| {
+                                last.instantaneous_power.max(0) as f64
+                                    * (d.as_secs_f64() - last_d.as_secs_f64())
+                            });
+                        r.accumulated_power = Some(accumulated_energy as u32);
+
+                        if let Some((crank_rpm, new_crank_count)) =
+                            last.checked_crank_rpm_and_new_count(&measurement)
+                        {
+                            crank_count += new_crank_count;
+                            r.total_cycles = Some(crank_count);
+                            // Prefer a dedicated cadence sensor when we have one
+                            if last_cadence_csc_measurement.is_none() {
+                                r.cadence = Some(crank_rpm as u8);
+                            }
+                        }
+
+                        // Prefer a dedicated speed sensor when we have one
+                        if last_wheel_csc_measurement.is_none() {
+                            if let Some((wheel_rpm, new_wheel_count)) =
+                                last.checked_wheel_rpm_and_new_count(&measurement)
+                            {
+                                r.speed = Some(wheel_rpm as f32 * WHEEL_CIRCUMFERENCE / 60.0);
+                                wheel_count += new_wheel_count;
+                                r.distance = Some(wheel_count as f64 * WHEEL_CIRCUMFERENCE as f64);
+                            }
+                        }
+                    }
+                    last_power_measurement = Some((measurement, d));
+                    r
+                }
+                telemetry_db::Notification::Ble((fitness_machine::INDOOR_BIKE_DATA_UUID, v)) => {
+                    let data = parse_indoor_bike_data(&v);
+                    if let Some(power) = data.instantaneous_power {
+                        let p = power.max(0) as u16;
+                        // Without torque, we assume the last power was held
+                        // until now
+                        if let (Some(last), Some(last_d)) = (last_power, last_ftms_elapsed) {
+                            accumulated_energy +=
+                                last as f64 * (d.as_secs_f64() - last_d.as_secs_f64());
+                            r.accumulated_power = Some(accumulated_energy as u32);
+                        }
+                        last_power = Some(p);
+                        last_ftms_elapsed = Some(d);
+                        r.power = Some(p);
+                    }
+                    // Prefer dedicated sensors when we have them
+                    if last_cadence_csc_measurement.is_none() {
+                        r.cadence = data.instantaneous_cadence.map(|x| x as u8);
+                    }
+                    if last_wheel_csc_measurement.is_none() {
+                        r.speed = data.instantaneous_speed;
+                        r.distance = data.total_distance.map(|x| x as f64);
+                    }
                     r
                 }
                 telemetry_db::Notification::Ble((csc_measurement::MEASURE_UUID, v)) => {
@@ -655,5 +1272,18 @@ fn db_session_to_fit(db: &telemetry_db::TelemetryDb, session_key: u64) -> Vec<u8
         }
     }
 
-    fit::to_file(&records)
+    // The final record is still pending once we run out of entries
+    if let Some(mut r) = record {
+        if let None = r.power {
+            r.power = last_power;
+        }
+        records.push(r);
+    }
+
+    fit::FitActivity {
+        records,
+        laps,
+        rr_intervals,
+        pauses,
+    }
 }
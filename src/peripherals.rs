@@ -1,4 +1,7 @@
 pub mod cadence;
+pub mod ftms;
 pub mod hrm;
 pub mod kickr;
+pub mod sensor;
 pub mod speed;
+pub mod trainer;
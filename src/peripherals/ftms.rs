@@ -0,0 +1,141 @@
+use crate::ble::fitness_machine::{
+    parse_response, request_control_command, resistance_command, sim_command, target_power_command,
+    CONTROL_POINT_UUID, INDOOR_BIKE_DATA_UUID, SERVICE_UUID,
+};
+use crate::ble::trainer_control::{Response, SimSettings};
+use crate::peripherals::sensor::{find_characteristic, Discovery, Sensor, SensorKind};
+use crate::peripherals::trainer::{self, send, CommandResult, Mode, Trainer};
+use btleplug::api::{Central, Characteristic, NotificationHandler, Peripheral};
+use btleplug::Result;
+use std::sync::{mpsc, Arc, Mutex};
+
+// Any trainer that advertises FTMS, whatever it's called.  Kickrs may as well,
+// but they're handled by kickr::Kickr instead.
+pub const KIND: SensorKind = SensorKind {
+    name: "FTMS trainer",
+    role: trainer::ROLE,
+    discovery: Discovery::Service(SERVICE_UUID),
+    subscriptions: &[INDOOR_BIKE_DATA_UUID, CONTROL_POINT_UUID],
+};
+
+// A smart trainer that speaks the standard Fitness Machine Service
+pub struct Ftms<C: Central<P>, P: Peripheral> {
+    sensor: Sensor<C, P>,
+    control_point_char: Characteristic,
+    mode: Arc<Mutex<Option<Mode>>>,
+    // Everything the trainer says back to us on the control point
+    responses: Arc<Mutex<mpsc::Receiver<Response>>>,
+}
+
+impl<P: Peripheral, C: Central<P> + 'static> Ftms<C, P> {
+    // Returns None if no trainer was found, or if it turns out not to have a
+    // control point after all.
+    pub fn new(central: C, paired: Option<&str>) -> Result<Option<Self>> {
+        let mode = Arc::new(Mutex::new(None));
+        let (responses_tx, responses_rx) = mpsc::channel();
+        let responses = Arc::new(Mutex::new(responses_rx));
+
+        // Control is lost along with the connection, so we take it back
+        // before restoring the mode.  Reconnects have their own thread, so
+        // it's fine to wait for the trainer to acknowledge each, and if it
+        // doesn't, the reconnect is tried again.
+        let mode_for_reconnects = mode.clone();
+        let responses_for_reconnects = responses.clone();
+        let sensor = Sensor::connect_with(central, &KIND, paired, move |p| {
+            let control_point_char = find_characteristic(p, CONTROL_POINT_UUID)
+                .ok_or_else(|| btleplug::Error::Other("No control point".into()))?;
+            send(
+                p,
+                &control_point_char,
+                &responses_for_reconnects,
+                &request_control_command(),
+            )?;
+            let mode = *mode_for_reconnects.lock().unwrap();
+            if let Some(mode) = mode {
+                set_mode(p, &control_point_char, &responses_for_reconnects, mode)?;
+            }
+            Ok(())
+        })?;
+        let sensor = match sensor {
+            Some(sensor) => sensor,
+            None => return Ok(None),
+        };
+
+        let control_point_char =
+            find_characteristic(sensor.peripheral(), CONTROL_POINT_UUID).unwrap();
+
+        sensor.on_notification(Box::new(move |n| {
+            if n.uuid == CONTROL_POINT_UUID {
+                if let Some(response) = parse_response(&n.value) {
+                    responses_tx.send(response).ok();
+                }
+            }
+        }));
+
+        // Without control, this trainer is no use to us
+        send(
+            sensor.peripheral(),
+            &control_point_char,
+            &responses,
+            &request_control_command(),
+        )?;
+        println!("Took control of FTMS trainer");
+
+        Ok(Some(Ftms {
+            sensor,
+            control_point_char,
+            mode,
+            responses,
+        }))
+    }
+
+    // The mode is only locked while it's updated, not while we wait for the
+    // trainer, so that a reconnect never waits on our retries.
+    fn change_mode(&self, mode: Mode) -> CommandResult {
+        *self.mode.lock().unwrap() = Some(mode);
+        set_mode(
+            self.sensor.peripheral(),
+            &self.control_point_char,
+            &self.responses,
+            mode,
+        )
+    }
+}
+
+impl<P: Peripheral, C: Central<P> + 'static> Trainer for Ftms<C, P> {
+    fn set_power(&self, power: u16) -> CommandResult {
+        self.change_mode(Mode::Erg(power))
+    }
+
+    fn set_resistance(&self, percent: f32) -> CommandResult {
+        self.change_mode(Mode::Resistance(percent))
+    }
+
+    fn set_grade(&self, grade: f32) -> CommandResult {
+        let settings = match *self.mode.lock().unwrap() {
+            Some(Mode::Sim(settings, _)) => settings,
+            _ => SimSettings::default(),
+        };
+        self.change_mode(Mode::Sim(settings, grade))
+    }
+
+    fn on_notification(&self, cb: NotificationHandler) {
+        self.sensor.on_notification(cb)
+    }
+}
+
+// Unlike the Kickr, every mode is a single command, since the grade is sent
+// along with the rest of the simulation.
+fn set_mode(
+    peripheral: &impl Peripheral,
+    control_point_char: &Characteristic,
+    responses: &Mutex<mpsc::Receiver<Response>>,
+    mode: Mode,
+) -> CommandResult {
+    let command = match mode {
+        Mode::Erg(power) => target_power_command(power),
+        Mode::Resistance(percent) => resistance_command(percent),
+        Mode::Sim(settings, grade) => sim_command(&settings, grade),
+    };
+    send(peripheral, control_point_char, responses, &command)
+}
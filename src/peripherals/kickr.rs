@@ -1,10 +1,15 @@
-use btleplug::api::{Central, CentralEvent, Characteristic, NotificationHandler, Peripheral, UUID};
+use crate::ble::trainer_control::{Response, SimSettings};
+use crate::ble::wahoo_trainer::{
+    erg_command, grade_command, parse_response, parse_spindown, resistance_command, sim_command,
+    spindown_command, SpindownStatus,
+};
+use crate::peripherals::sensor::{find_characteristic, Discovery, Sensor, SensorKind};
+use crate::peripherals::trainer::{self, send, CommandResult, Mode, Trainer};
+use btleplug::api::{Central, Characteristic, NotificationHandler, Peripheral, UUID};
 use btleplug::Result;
 use std::{
-    marker::PhantomData,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 const UNLOCK_UUID: UUID = UUID::B128([
@@ -21,126 +26,170 @@ pub const CONTROL_UUID: UUID = UUID::B128([
     0x8B, 0xEB, 0x9F, 0x0F, 0x50, 0xF1, 0xFA, 0x97, 0xB3, 0x4A, 0x7D, 0x0A, 0x05, 0xE0, 0x26, 0xA0,
 ]);
 
+pub const KIND: SensorKind = SensorKind {
+    name: "KICKR",
+    role: trainer::ROLE,
+    discovery: Discovery::LocalNames(&["KICKR"]),
+    subscriptions: &[MEASURE_UUID, TRAINER_UUID],
+};
+
 pub struct Kickr<C: Central<P>, P: Peripheral> {
-    peripheral: P,
+    sensor: Sensor<C, P>,
     power_control_char: Characteristic,
-    target_power: Arc<Mutex<Option<u16>>>,
-    central: PhantomData<C>,
+    mode: Arc<Mutex<Option<Mode>>>,
+    // Everything the trainer says back to us on the control characteristic
+    responses: Arc<Mutex<mpsc::Receiver<Response>>>,
 }
 
 impl<P: Peripheral, C: Central<P> + 'static> Kickr<C, P> {
-    // TODO: It may make sense to use Type States to separate out new (Optional)
-    // and connect (Result).  For this app, we really only care about
-    // permanently connecting (but it would be nice to clean up connections on
-    // exit).
-    pub fn new(central: C) -> Result<Option<Self>> {
-        match central.peripherals().into_iter().find(is_kickr) {
-            None => Ok(None),
-            Some(peripheral) => {
-                peripheral.connect()?;
-                println!("Connected to KICKR");
-
-                peripheral.discover_characteristics()?;
-                println!("All characteristics discovered");
-
-                first_time_setup(&peripheral)?;
-                unlock(&peripheral)?;
-
-                let power_control_char = peripheral
-                    .characteristics()
-                    .into_iter()
-                    .find(|c| c.uuid == CONTROL_UUID)
-                    // Kickr with a Control UUID is an invariant
-                    .unwrap();
-
-                let target_power = Arc::new(Mutex::new(None));
-
-                let central_for_disconnects = central.clone();
-                let tp_for_disconnects = target_power.clone();
-                let pcc_for_disconnects = power_control_char.clone();
-
-                // TODO: How on earth do we handle errors here???
-                // Potentially we just keep retrying with exponential back-off?
-                central.on_event(Box::new(move |evt| {
-                    if let CentralEvent::DeviceDisconnected(addr) = evt {
-                        let p = central_for_disconnects.peripheral(addr).unwrap();
-                        if is_kickr(&p) {
-                            thread::sleep(Duration::from_secs(2));
-                            p.connect().unwrap();
-                            unlock(&p).unwrap();
-                            if let Some(power) = *(tp_for_disconnects.lock().unwrap()) {
-                                set_power(&p, &pcc_for_disconnects, power).unwrap();
-                            }
-                        }
-                    }
-                }));
-
-                Ok(Some(Kickr {
-                    peripheral,
-                    power_control_char,
-                    target_power,
-                    central: PhantomData,
-                }))
+    pub fn new(central: C, paired: Option<&str>) -> Result<Option<Self>> {
+        let mode = Arc::new(Mutex::new(None));
+        let (responses_tx, responses_rx) = mpsc::channel();
+        let responses = Arc::new(Mutex::new(responses_rx));
+
+        let mode_for_reconnects = mode.clone();
+        let responses_for_reconnects = responses.clone();
+        // Reconnects have their own thread, so it's fine to wait for the
+        // trainer to acknowledge the mode, and if it doesn't, the reconnect is
+        // tried again.
+        let sensor = Sensor::connect_with(central, &KIND, paired, move |p| {
+            unlock(p)?;
+            let mode = *mode_for_reconnects.lock().unwrap();
+            if let Some(mode) = mode {
+                let power_control_char = find_characteristic(p, CONTROL_UUID)
+                    .ok_or_else(|| btleplug::Error::Other("No control characteristic".into()))?;
+                set_mode(p, &power_control_char, &responses_for_reconnects, mode)?;
+            }
+            Ok(())
+        })?;
+        let sensor = match sensor {
+            Some(sensor) => sensor,
+            None => return Ok(None),
+        };
+
+        unlock(sensor.peripheral())?;
+
+        // Kickr with a Control UUID is an invariant
+        let power_control_char = find_characteristic(sensor.peripheral(), CONTROL_UUID).unwrap();
+
+        sensor.on_notification(Box::new(move |n| {
+            if n.uuid == CONTROL_UUID {
+                if let Some(response) = parse_response(&n.value) {
+                    responses_tx.send(response).ok();
+                }
+            }
+        }));
+
+        Ok(Some(Kickr {
+            sensor,
+            power_control_char,
+            mode,
+            responses,
+        }))
+    }
+
+    // Runs a spindown calibration, calling on_status as the trainer reports
+    // each stage.  Returns how long the flywheel took to spin down, or None if
+    // the trainer reported a failure or we heard nothing back before timeout.
+    pub fn spindown<F: FnMut(SpindownStatus)>(
+        &self,
+        timeout: Duration,
+        mut on_status: F,
+    ) -> Result<Option<Duration>> {
+        // We hold on to responses for the whole spindown, since each stage
+        // arrives as another response to the one command
+        let responses = self.responses.lock().unwrap();
+        responses.try_iter().for_each(drop);
+        self.sensor
+            .peripheral()
+            .request(&self.power_control_char, &spindown_command())?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = responses
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map(|r| parse_spindown(&r));
+            match status {
+                // Responses to anything else
+                Ok(None) => (),
+                Ok(Some(SpindownStatus::Complete(spindown))) => {
+                    on_status(SpindownStatus::Complete(spindown));
+                    break Ok(Some(spindown));
+                }
+                Ok(Some(SpindownStatus::Failed)) | Err(_) => {
+                    on_status(SpindownStatus::Failed);
+                    break Ok(None);
+                }
+                Ok(Some(status)) => on_status(status),
             }
         }
     }
 
-    pub fn set_power(&self, power: u16) -> Result<()> {
-        // TODO: We get a notification when this is done, so if we hold the lock
-        // until then, we can use eventing to ensure a good sync.
-        let mut tp_guard = self.target_power.lock().unwrap();
-        *tp_guard = Some(power);
+    // Identifies this particular trainer, since each one is calibrated
+    // separately
+    pub fn address(&self) -> String {
+        self.sensor.peripheral().address().to_string()
+    }
 
-        set_power(&self.peripheral, &self.power_control_char, power)
+    fn send(&self, command: &[u8]) -> CommandResult {
+        send(
+            self.sensor.peripheral(),
+            &self.power_control_char,
+            &self.responses,
+            command,
+        )
     }
 
-    // TODO: Make this scoped just to power or just more specific in general?
-    pub fn on_notification(&self, cb: NotificationHandler) {
-        self.peripheral.on_notification(cb)
+    fn set_mode(&self, mode: Mode) -> CommandResult {
+        set_mode(
+            self.sensor.peripheral(),
+            &self.power_control_char,
+            &self.responses,
+            mode,
+        )
     }
 }
 
-impl<C: Central<P>, P: Peripheral> Drop for Kickr<C, P> {
-    fn drop(&mut self) {
-        self.peripheral.clear_notification_handlers();
+// The mode is only locked while it's updated, not while we wait for the
+// trainer, so that a reconnect never waits on our retries.
+impl<P: Peripheral, C: Central<P> + 'static> Trainer for Kickr<C, P> {
+    fn set_power(&self, power: u16) -> CommandResult {
+        *self.mode.lock().unwrap() = Some(Mode::Erg(power));
+        self.send(&erg_command(power))
     }
-}
 
-fn is_kickr(p: &impl Peripheral) -> bool {
-    p.properties()
-        .local_name
-        .iter()
-        .any(|name| name.contains("KICKR"))
-}
+    fn set_resistance(&self, percent: f32) -> CommandResult {
+        *self.mode.lock().unwrap() = Some(Mode::Resistance(percent));
+        self.send(&resistance_command(percent))
+    }
 
-fn first_time_setup(kickr: &impl Peripheral) -> Result<()> {
-    let power_measurement = kickr
-        .characteristics()
-        .into_iter()
-        .find(|c| c.uuid == MEASURE_UUID)
-        .unwrap();
-
-    kickr.subscribe(&power_measurement)?;
-    println!("Subscribed to power measure");
-
-    let trainer_characteristic = kickr
-        .characteristics()
-        .into_iter()
-        .find(|c| c.uuid == TRAINER_UUID)
-        .unwrap();
-    println!("Trainer char found.");
-
-    kickr.subscribe(&trainer_characteristic)?;
-    println!("Subscribed to trainer characteristic");
-    Ok(())
+    // Once we're simulating, only the grade needs to be sent
+    fn set_grade(&self, grade: f32) -> CommandResult {
+        let (settings, was_simulating) = {
+            let mut mode_guard = self.mode.lock().unwrap();
+            let (settings, was_simulating) = match *mode_guard {
+                Some(Mode::Sim(settings, _)) => (settings, true),
+                _ => (SimSettings::default(), false),
+            };
+            *mode_guard = Some(Mode::Sim(settings, grade));
+            (settings, was_simulating)
+        };
+
+        if was_simulating {
+            self.send(&grade_command(grade))
+        } else {
+            self.set_mode(Mode::Sim(settings, grade))
+        }
+    }
+
+    // TODO: Make this scoped just to power or just more specific in general?
+    fn on_notification(&self, cb: NotificationHandler) {
+        self.sensor.on_notification(cb)
+    }
 }
 
 fn unlock(kickr: &impl Peripheral) -> Result<()> {
-    let unlock_characteristic = kickr
-        .characteristics()
-        .into_iter()
-        .find(|c| c.uuid == UNLOCK_UUID)
-        .unwrap();
+    let unlock_characteristic = find_characteristic(kickr, UNLOCK_UUID).unwrap();
     println!("Unlock char found.");
 
     kickr.command(&unlock_characteristic, &[0x20, 0xee, 0xfc])?;
@@ -148,15 +197,39 @@ fn unlock(kickr: &impl Peripheral) -> Result<()> {
     Ok(())
 }
 
-fn set_power(
+fn set_mode(
     peripheral: &impl Peripheral,
     power_control_char: &Characteristic,
-    power: u16,
-) -> Result<()> {
-    peripheral.request(
-        power_control_char,
-        &[0x42, (power & 0xff) as u8, ((power >> 8) & 0xff) as u8],
-    )?;
-    thread::sleep(Duration::from_secs(1));
-    Ok(())
+    responses: &Mutex<mpsc::Receiver<Response>>,
+    mode: Mode,
+) -> CommandResult {
+    match mode {
+        Mode::Erg(power) => send(
+            peripheral,
+            power_control_char,
+            responses,
+            &erg_command(power),
+        ),
+        Mode::Resistance(percent) => send(
+            peripheral,
+            power_control_char,
+            responses,
+            &resistance_command(percent),
+        ),
+        // The model has to be set before the grade means anything
+        Mode::Sim(settings, grade) => {
+            send(
+                peripheral,
+                power_control_char,
+                responses,
+                &sim_command(&settings),
+            )?;
+            send(
+                peripheral,
+                power_control_char,
+                responses,
+                &grade_command(grade),
+            )
+        }
+    }
 }
@@ -0,0 +1,198 @@
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, Characteristic, NotificationHandler, Peripheral, UUID,
+};
+use btleplug::Result;
+use std::{marker::PhantomData, sync::Arc, thread, time::Duration};
+
+// What a sensor is used for, which is what riders pair them by.  Every trainer
+// shares a role, since we only ever ride with one.
+pub const ROLES: [&str; 4] = ["cadence", "hrm", "speed", "trainer"];
+
+// How long we wait before trying to reconnect, which doubles after each
+// failed attempt, up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// How we recognize a sensor that hasn't been paired
+pub enum Discovery {
+    // The first peripheral whose local name contains any of these
+    LocalNames(&'static [&'static str]),
+    // The first peripheral that advertises this service, for standard
+    // services that sensors of any name may have
+    Service(UUID),
+}
+
+// How we find a kind of sensor, and what we want from it
+pub struct SensorKind {
+    // Only used for logging
+    pub name: &'static str,
+    // One of ROLES
+    pub role: &'static str,
+    pub discovery: Discovery,
+    // Characteristics that are subscribed to as soon as we connect
+    pub subscriptions: &'static [UUID],
+}
+
+impl SensorKind {
+    // Both are known from advertisements, without connecting
+    pub fn matches(&self, p: &impl Peripheral) -> bool {
+        match self.discovery {
+            Discovery::LocalNames(names) => p
+                .properties()
+                .local_name
+                .iter()
+                .any(|name| names.iter().any(|n| name.contains(n))),
+            Discovery::Service(uuid) => p.properties().services.contains(&uuid),
+        }
+    }
+
+    // A paired sensor is the only one we'll use, even if it isn't around, so
+    // that we never pick up someone else's.
+    pub fn find<P: Peripheral>(
+        &self,
+        central: &impl Central<P>,
+        paired: Option<&str>,
+    ) -> Option<P> {
+        let peripherals = central.peripherals();
+        match paired {
+            Some(address) => peripherals
+                .into_iter()
+                .find(|p| p.address().to_string() == address),
+            None => peripherals.into_iter().find(|p| self.matches(p)),
+        }
+    }
+}
+
+// A connected peripheral that's subscribed to everything its kind asks for,
+// and that reconnects itself whenever it's lost.
+pub struct Sensor<C: Central<P>, P: Peripheral> {
+    peripheral: P,
+    central: PhantomData<C>,
+}
+
+impl<P: Peripheral, C: Central<P> + 'static> Sensor<C, P> {
+    // TODO: It may make sense to use Type States to separate out new (Optional)
+    // and connect (Result).  For this app, we really only care about
+    // permanently connecting (but it would be nice to clean up connections on
+    // exit).
+    pub fn connect(
+        central: C,
+        kind: &'static SensorKind,
+        paired: Option<&str>,
+    ) -> Result<Option<Self>> {
+        Sensor::connect_with(central, kind, paired, |_| Ok(()))
+    }
+
+    // Like connect, but on_reconnect is called each time the peripheral comes
+    // back, for sensors that need more than a connection (like trainers, which
+    // have to be told what to do again).  If it fails, we try reconnecting
+    // again.  Returns None if nothing matches, or if what matched is missing
+    // any of the subscriptions.
+    pub fn connect_with<F>(
+        central: C,
+        kind: &'static SensorKind,
+        paired: Option<&str>,
+        on_reconnect: F,
+    ) -> Result<Option<Self>>
+    where
+        F: Fn(&P) -> Result<()> + Send + Sync + 'static,
+    {
+        match kind.find(&central, paired) {
+            None => Ok(None),
+            Some(peripheral) => {
+                // The address is what's needed to pair it
+                println!("Found {} at {}", kind.name, peripheral.address());
+
+                if !peripheral.is_connected() {
+                    peripheral.connect()?;
+                }
+                println!("Connected to {}", kind.name);
+
+                peripheral.discover_characteristics()?;
+                println!("All characteristics discovered");
+
+                for uuid in kind.subscriptions {
+                    match find_characteristic(&peripheral, *uuid) {
+                        Some(c) => {
+                            peripheral.subscribe(&c)?;
+                            println!("Subscribed to {:?}", uuid);
+                        }
+                        // It wasn't the sensor we were looking for after all
+                        None => return Ok(None),
+                    }
+                }
+
+                let address = peripheral.address();
+                let name = kind.name;
+                let central_for_disconnects = central.clone();
+                let on_reconnect = Arc::new(on_reconnect);
+                // Reconnecting may take a while (or never happen), so it gets
+                // its own thread, leaving this one free for everyone else's
+                // events.
+                central.on_event(Box::new(move |evt| {
+                    if let CentralEvent::DeviceDisconnected(addr) = evt {
+                        if addr == address {
+                            let central = central_for_disconnects.clone();
+                            let on_reconnect = on_reconnect.clone();
+                            thread::spawn(move || {
+                                let mut delay = RECONNECT_DELAY;
+                                loop {
+                                    thread::sleep(delay);
+                                    match reconnect(&central, addr, &*on_reconnect) {
+                                        Ok(()) => {
+                                            println!("Reconnected to {}", name);
+                                            break;
+                                        }
+                                        Err(e) => {
+                                            println!("Could not reconnect to {}: {}", name, e);
+                                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                    }
+                }));
+
+                Ok(Some(Sensor {
+                    peripheral,
+                    central: PhantomData,
+                }))
+            }
+        }
+    }
+
+    pub fn peripheral(&self) -> &P {
+        &self.peripheral
+    }
+
+    // TODO: Make this scoped to a single characteristic?
+    pub fn on_notification(&self, cb: NotificationHandler) {
+        self.peripheral.on_notification(cb)
+    }
+}
+
+impl<C: Central<P>, P: Peripheral> Drop for Sensor<C, P> {
+    fn drop(&mut self) {
+        self.peripheral.clear_notification_handlers();
+    }
+}
+
+fn reconnect<P: Peripheral, C: Central<P>>(
+    central: &C,
+    address: BDAddr,
+    on_reconnect: &impl Fn(&P) -> Result<()>,
+) -> Result<()> {
+    let p = central
+        .peripheral(address)
+        .ok_or(btleplug::Error::DeviceNotFound)?;
+    // We may have connected on an attempt that failed after that
+    if !p.is_connected() {
+        p.connect()?;
+    }
+    on_reconnect(&p)
+}
+
+pub fn find_characteristic(p: &impl Peripheral, uuid: UUID) -> Option<Characteristic> {
+    p.characteristics().into_iter().find(|c| c.uuid == uuid)
+}
@@ -0,0 +1,144 @@
+use crate::ble::trainer_control::{Response, SimSettings};
+use crate::peripherals::{ftms::Ftms, kickr, kickr::Kickr};
+use btleplug::api::{Central, Characteristic, NotificationHandler, Peripheral};
+use btleplug::Result;
+use std::{
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How long we wait for the trainer to acknowledge a command, and how many times
+// we send it before giving up
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum CommandError {
+    Ble(btleplug::Error),
+    // The trainer answered, but refused the command with this opcode
+    Rejected(u8),
+    // The trainer never answered the command with this opcode
+    Unacknowledged(u8),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommandError::Ble(e) => write!(f, "Bluetooth error: {}", e),
+            CommandError::Rejected(opcode) => write!(f, "Command {:#04x} was rejected", opcode),
+            CommandError::Unacknowledged(opcode) => write!(
+                f,
+                "Command {:#04x} wasn't acknowledged after {} attempts",
+                opcode, ATTEMPTS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<btleplug::Error> for CommandError {
+    fn from(e: btleplug::Error) -> CommandError {
+        CommandError::Ble(e)
+    }
+}
+
+// For where only Bluetooth errors can be returned, like reconnects
+impl From<CommandError> for btleplug::Error {
+    fn from(e: CommandError) -> btleplug::Error {
+        match e {
+            CommandError::Ble(e) => e,
+            e => btleplug::Error::Other(e.to_string()),
+        }
+    }
+}
+
+pub type CommandResult = std::result::Result<(), CommandError>;
+
+// How the trainer decides on resistance, which we keep so it can be restored
+// after a reconnect
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    // Target power in Watts
+    Erg(u16),
+    // Percent of maximum resistance
+    Resistance(f32),
+    // Grade as a percent
+    Sim(SimSettings, f32),
+}
+
+// Sends a command and waits for the trainer to acknowledge it, sending it
+// again if we hear nothing back.  Commands start with their opcode, which is
+// echoed back in the response.
+pub fn send(
+    peripheral: &impl Peripheral,
+    control_char: &Characteristic,
+    responses: &Mutex<mpsc::Receiver<Response>>,
+    command: &[u8],
+) -> CommandResult {
+    let opcode = command[0];
+    // Holding the lock keeps anyone else from taking our acknowledgement
+    let responses = responses.lock().unwrap();
+    for _ in 0..ATTEMPTS {
+        // Anything left over answers a command we've already given up on
+        responses.try_iter().for_each(drop);
+        peripheral.request(control_char, command)?;
+
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        while let Ok(response) =
+            responses.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if response.opcode == opcode {
+                return if response.success {
+                    Ok(())
+                } else {
+                    Err(CommandError::Rejected(opcode))
+                };
+            }
+        }
+    }
+    Err(CommandError::Unacknowledged(opcode))
+}
+
+// Every kind of trainer is paired as this
+pub const ROLE: &str = "trainer";
+
+// Anything we can ride indoors and control.  Each command returns once the
+// trainer has acknowledged it.
+pub trait Trainer: Send + Sync {
+    // Target power in Watts
+    fn set_power(&self, power: u16) -> CommandResult;
+
+    // Holds the brake at a percent of its maximum, regardless of speed
+    fn set_resistance(&self, percent: f32) -> CommandResult;
+
+    // Simulates riding on the road at this grade (a percent).  If we aren't
+    // already simulating, we start with default settings.
+    fn set_grade(&self, grade: f32) -> CommandResult;
+
+    fn on_notification(&self, cb: NotificationHandler);
+}
+
+// Kickrs are preferred, since they may speak FTMS too, but their own protocol
+// can do more (like spindowns).  A paired trainer that isn't named like a Kickr
+// is assumed to speak FTMS.
+pub fn connect<P: Peripheral + 'static, C: Central<P> + 'static>(
+    central: C,
+    paired: Option<&str>,
+) -> Result<Option<Box<dyn Trainer>>> {
+    let is_kickr = match paired {
+        Some(_) => kickr::KIND
+            .find(&central, paired)
+            .map_or(false, |p| kickr::KIND.matches(&p)),
+        None => true,
+    };
+    let kickr = if is_kickr {
+        Kickr::new(central.clone(), paired)?
+    } else {
+        None
+    };
+    match kickr {
+        Some(kickr) => Ok(Some(Box::new(kickr))),
+        None => Ok(Ftms::new(central, paired)?.map(|ftms| Box::new(ftms) as Box<dyn Trainer>)),
+    }
+}
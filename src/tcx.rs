@@ -0,0 +1,307 @@
+use crate::fit::{lap_summaries, FitActivity, FitRecord, LapSummary};
+use crate::utils::to_rfc3339;
+use std::fmt::Write;
+
+pub fn to_tcx(activity: &FitActivity) -> String {
+    let laps: String = lap_summaries(activity).iter().map(lap_to_string).collect();
+
+    let id = activity
+        .records
+        .first()
+        .map(|r| to_rfc3339(r.seconds_since_unix_epoch))
+        .unwrap_or_default();
+
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<TrainingCenterDatabase",
+            " xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\"",
+            " xmlns:ns3=\"http://www.garmin.com/xmlschemas/ActivityExtension/v2\">\n",
+            "  <Activities>\n",
+            "    <Activity Sport=\"Biking\">\n",
+            "      <Id>{}</Id>\n",
+            "{}",
+            "    </Activity>\n",
+            "  </Activities>\n",
+            "</TrainingCenterDatabase>\n",
+        ),
+        id, laps
+    )
+}
+
+fn lap_to_string(summary: &LapSummary) -> String {
+    let mut s = String::new();
+    write!(
+        s,
+        "      <Lap StartTime=\"{}\">\n",
+        to_rfc3339(summary.start_time)
+    )
+    .unwrap();
+    write!(
+        s,
+        "        <TotalTimeSeconds>{}</TotalTimeSeconds>\n",
+        summary.timer_time
+    )
+    .unwrap();
+    write!(
+        s,
+        "        <DistanceMeters>{:.2}</DistanceMeters>\n",
+        summary.distance.unwrap_or(0.0)
+    )
+    .unwrap();
+    if let Some(max_speed) = summary.max_speed {
+        write!(s, "        <MaximumSpeed>{:.3}</MaximumSpeed>\n", max_speed).unwrap();
+    }
+    // Calories are required, and with ~24% human efficiency, kJ of work is
+    // close enough to kcal burned.
+    let calories = summary
+        .avg_power
+        .map(|p| p as u32 * summary.timer_time / 1000)
+        .unwrap_or(0);
+    write!(s, "        <Calories>{}</Calories>\n", calories).unwrap();
+    if let Some(hr) = summary.avg_heart_rate {
+        write!(
+            s,
+            "        <AverageHeartRateBpm><Value>{}</Value></AverageHeartRateBpm>\n",
+            hr
+        )
+        .unwrap();
+    }
+    if let Some(hr) = summary.max_heart_rate {
+        write!(
+            s,
+            "        <MaximumHeartRateBpm><Value>{}</Value></MaximumHeartRateBpm>\n",
+            hr
+        )
+        .unwrap();
+    }
+    s.push_str("        <Intensity>Active</Intensity>\n");
+    if let Some(cadence) = summary.avg_cadence {
+        write!(s, "        <Cadence>{}</Cadence>\n", cadence).unwrap();
+    }
+    // Workout steps end when their time is up, anything else is the rider
+    let trigger_method = match summary.lap.and_then(|l| l.planned_duration) {
+        Some(_) => "Time",
+        None => "Manual",
+    };
+    write!(
+        s,
+        "        <TriggerMethod>{}</TriggerMethod>\n",
+        trigger_method
+    )
+    .unwrap();
+
+    s.push_str("        <Track>\n");
+    for r in summary.records {
+        s.push_str(&trackpoint_to_string(r));
+    }
+    s.push_str("        </Track>\n");
+
+    if summary.avg_speed.is_some() || summary.avg_power.is_some() {
+        s.push_str("        <Extensions>\n");
+        s.push_str("          <ns3:LX>\n");
+        if let Some(avg_speed) = summary.avg_speed {
+            write!(
+                s,
+                "            <ns3:AvgSpeed>{:.3}</ns3:AvgSpeed>\n",
+                avg_speed
+            )
+            .unwrap();
+        }
+        if let Some(avg_power) = summary.avg_power {
+            write!(
+                s,
+                "            <ns3:AvgWatts>{}</ns3:AvgWatts>\n",
+                avg_power
+            )
+            .unwrap();
+        }
+        if let Some(max_power) = summary.max_power {
+            write!(
+                s,
+                "            <ns3:MaxWatts>{}</ns3:MaxWatts>\n",
+                max_power
+            )
+            .unwrap();
+        }
+        s.push_str("          </ns3:LX>\n");
+        s.push_str("        </Extensions>\n");
+    }
+    s.push_str("      </Lap>\n");
+    s
+}
+
+// The schema is strict about element order, so these are written in the order
+// it declares them.
+fn trackpoint_to_string(r: &FitRecord) -> String {
+    let mut s = String::from("          <Trackpoint>\n");
+    write!(
+        s,
+        "            <Time>{}</Time>\n",
+        to_rfc3339(r.seconds_since_unix_epoch)
+    )
+    .unwrap();
+    if let (Some(lat), Some(lon)) = (r.latitude, r.longitude) {
+        write!(
+            s,
+            concat!(
+                "            <Position>\n",
+                "              <LatitudeDegrees>{:.7}</LatitudeDegrees>\n",
+                "              <LongitudeDegrees>{:.7}</LongitudeDegrees>\n",
+                "            </Position>\n",
+            ),
+            lat, lon
+        )
+        .unwrap();
+    }
+    if let Some(altitude) = r.altitude {
+        write!(
+            s,
+            "            <AltitudeMeters>{:.1}</AltitudeMeters>\n",
+            altitude
+        )
+        .unwrap();
+    }
+    if let Some(distance) = r.distance {
+        write!(
+            s,
+            "            <DistanceMeters>{:.2}</DistanceMeters>\n",
+            distance
+        )
+        .unwrap();
+    }
+    if let Some(hr) = r.heart_rate {
+        write!(
+            s,
+            "            <HeartRateBpm><Value>{}</Value></HeartRateBpm>\n",
+            hr
+        )
+        .unwrap();
+    }
+    if let Some(cadence) = r.cadence {
+        write!(s, "            <Cadence>{}</Cadence>\n", cadence).unwrap();
+    }
+    if r.speed.is_some() || r.power.is_some() {
+        s.push_str("            <Extensions>\n");
+        s.push_str("              <ns3:TPX>\n");
+        if let Some(speed) = r.speed {
+            write!(s, "                <ns3:Speed>{:.3}</ns3:Speed>\n", speed).unwrap();
+        }
+        if let Some(power) = r.power {
+            write!(s, "                <ns3:Watts>{}</ns3:Watts>\n", power).unwrap();
+        }
+        s.push_str("              </ns3:TPX>\n");
+        s.push_str("            </Extensions>\n");
+    }
+    s.push_str("          </Trackpoint>\n");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_tcx;
+    use crate::fit::{FitActivity, FitLap, FitRecord};
+    use std::time::Duration;
+
+    fn record(t: u32, power: u16, cadence: u8, distance: f64) -> FitRecord {
+        FitRecord {
+            seconds_since_unix_epoch: t,
+            power: Some(power),
+            heart_rate: None,
+            cadence: Some(cadence),
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: Some(distance),
+            speed: None,
+            left_right_balance: None,
+            accumulated_power: None,
+            total_cycles: None,
+        }
+    }
+
+    #[test]
+    fn to_tcx_empty() {
+        assert_eq!(
+            to_tcx(&FitActivity {
+                records: vec![],
+                laps: vec![],
+                rr_intervals: vec![],
+                pauses: vec![],
+            }),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<TrainingCenterDatabase",
+                " xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\"",
+                " xmlns:ns3=\"http://www.garmin.com/xmlschemas/ActivityExtension/v2\">\n",
+                "  <Activities>\n",
+                "    <Activity Sport=\"Biking\">\n",
+                "      <Id></Id>\n",
+                "    </Activity>\n",
+                "  </Activities>\n",
+                "</TrainingCenterDatabase>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn to_tcx_with_laps() {
+        let tcx = to_tcx(&FitActivity {
+            records: vec![
+                record(1586000000, 100, 80, 0.0),
+                record(1586000001, 200, 90, 10.0),
+                record(1586000002, 300, 100, 25.0),
+            ],
+            laps: vec![
+                FitLap {
+                    start: 1586000000,
+                    target_power: Some(150),
+                    planned_duration: Some(Duration::from_secs(2)),
+                },
+                FitLap {
+                    start: 1586000002,
+                    target_power: Some(300),
+                    planned_duration: None,
+                },
+            ],
+            rr_intervals: vec![],
+            pauses: vec![],
+        });
+
+        assert!(tcx.contains("      <Id>2020-04-04T11:33:20Z</Id>\n"));
+        assert!(tcx.contains(concat!(
+            "      <Lap StartTime=\"2020-04-04T11:33:20Z\">\n",
+            "        <TotalTimeSeconds>2</TotalTimeSeconds>\n",
+            "        <DistanceMeters>10.00</DistanceMeters>\n",
+            "        <Calories>0</Calories>\n",
+            "        <Intensity>Active</Intensity>\n",
+            "        <Cadence>85</Cadence>\n",
+            "        <TriggerMethod>Time</TriggerMethod>\n",
+            "        <Track>\n",
+            "          <Trackpoint>\n",
+            "            <Time>2020-04-04T11:33:20Z</Time>\n",
+            "            <DistanceMeters>0.00</DistanceMeters>\n",
+            "            <Cadence>80</Cadence>\n",
+            "            <Extensions>\n",
+            "              <ns3:TPX>\n",
+            "                <ns3:Watts>100</ns3:Watts>\n",
+            "              </ns3:TPX>\n",
+            "            </Extensions>\n",
+            "          </Trackpoint>\n",
+        )));
+        assert!(tcx.contains(concat!(
+            "        </Track>\n",
+            "        <Extensions>\n",
+            "          <ns3:LX>\n",
+            "            <ns3:AvgWatts>150</ns3:AvgWatts>\n",
+            "            <ns3:MaxWatts>200</ns3:MaxWatts>\n",
+            "          </ns3:LX>\n",
+            "        </Extensions>\n",
+            "      </Lap>\n",
+            "      <Lap StartTime=\"2020-04-04T11:33:22Z\">\n",
+            "        <TotalTimeSeconds>0</TotalTimeSeconds>\n",
+            "        <DistanceMeters>15.00</DistanceMeters>\n",
+        )));
+        assert!(tcx.contains("        <TriggerMethod>Manual</TriggerMethod>\n"));
+    }
+}
@@ -7,6 +7,9 @@ use std::time::Duration;
 #[derive(Clone)]
 pub struct TelemetryDb {
     db: sled::Db,
+    profiles: sled::Tree,
+    calibrations: sled::Tree,
+    pairings: sled::Tree,
     serial_config: bincode::Config,
 }
 
@@ -20,18 +23,50 @@ pub struct TelemetryDb {
 pub enum Notification {
     Ble((UUID, Vec<u8>)),
     Gps(ParseResult),
+    // A workout moved on to a new step: its index, its target power, and how
+    // long it's scheduled to last (None for the indefinite tail).
+    WorkoutStep((u32, u16, Option<Duration>)),
+    // The rider paused (true) or resumed (false) their workout
+    Paused(bool),
+}
+
+// What we know about a rider, keyed by their name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    // In Watts, used to resolve workouts written as a percent of FTP
+    pub ftp: u16,
+}
+
+// The latest spindown of a trainer, keyed by its address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub spindown: Duration,
+    pub seconds_since_unix_epoch: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 enum NotificationType {
     Ble(UUID),
     Gps,
+    WorkoutStep,
+    Paused,
 }
 
 pub fn open(path: String) -> sled::Result<TelemetryDb> {
     let db = sled::open(path)?;
+    // Profiles live in their own tree so they never show up as sessions
+    let profiles = db.open_tree("profiles")?;
+    let calibrations = db.open_tree("calibrations")?;
+    // The address of each rider's sensors, keyed by rider and role
+    let pairings = db.open_tree("pairings")?;
     let serial_config = bincode::config().big_endian().clone();
-    Ok(TelemetryDb { db, serial_config })
+    Ok(TelemetryDb {
+        db,
+        profiles,
+        calibrations,
+        pairings,
+        serial_config,
+    })
 }
 
 pub fn open_default() -> sled::Result<TelemetryDb> {
@@ -48,6 +83,8 @@ impl TelemetryDb {
         let nt = match notification {
             Notification::Gps(_) => NotificationType::Gps,
             Notification::Ble((uuid, _)) => NotificationType::Ble(uuid),
+            Notification::WorkoutStep(_) => NotificationType::WorkoutStep,
+            Notification::Paused(_) => NotificationType::Paused,
         };
         // I can't imagine why this would fail...
         let key = self
@@ -105,4 +142,54 @@ impl TelemetryDb {
             })
         })
     }
+
+    pub fn get_profile(&self, rider: &str) -> sled::Result<Option<Profile>> {
+        let x = self.profiles.get(rider.as_bytes())?;
+        Ok(x.map(|v| {
+            let z: Vec<u8> = (*v).try_into().unwrap();
+            self.serial_config.deserialize(&z).unwrap()
+        }))
+    }
+
+    pub fn set_profile(&self, rider: &str, profile: &Profile) -> sled::Result<()> {
+        let value = self.serial_config.serialize(profile).unwrap();
+        self.profiles.insert(rider.as_bytes(), value)?;
+        Ok(())
+    }
+
+    pub fn get_calibration(&self, trainer: &str) -> sled::Result<Option<Calibration>> {
+        let x = self.calibrations.get(trainer.as_bytes())?;
+        Ok(x.map(|v| {
+            let z: Vec<u8> = (*v).try_into().unwrap();
+            self.serial_config.deserialize(&z).unwrap()
+        }))
+    }
+
+    pub fn set_calibration(&self, trainer: &str, calibration: &Calibration) -> sled::Result<()> {
+        let value = self.serial_config.serialize(calibration).unwrap();
+        self.calibrations.insert(trainer.as_bytes(), value)?;
+        Ok(())
+    }
+
+    pub fn get_pairing(&self, rider: &str, role: &str) -> sled::Result<Option<String>> {
+        let x = self.pairings.get(pairing_key(rider, role))?;
+        Ok(x.map(|v| String::from_utf8(v.to_vec()).unwrap()))
+    }
+
+    pub fn set_pairing(&self, rider: &str, role: &str, address: &str) -> sled::Result<()> {
+        self.pairings
+            .insert(pairing_key(rider, role), address.as_bytes())?;
+        Ok(())
+    }
+
+    // The rider goes back to using whichever sensor of this role is found first
+    pub fn remove_pairing(&self, rider: &str, role: &str) -> sled::Result<()> {
+        self.pairings.remove(pairing_key(rider, role))?;
+        Ok(())
+    }
+}
+
+// Rider names and roles are plain words, so they can't run together
+fn pairing_key(rider: &str, role: &str) -> Vec<u8> {
+    format!("{}/{}", rider, role).into_bytes()
 }
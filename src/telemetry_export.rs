@@ -0,0 +1,540 @@
+use crate::ble::{
+    csc_measurement, cycling_power_measurement::parse_cycling_power_measurement,
+    cycling_power_measurement::AccumulatedTorqueSource, fitness_machine,
+    heart_rate_measurement::parse_hrm, revolution_data::RevolutionData,
+};
+use crate::peripherals::{hrm, kickr};
+use crate::telemetry_db::Notification;
+use btleplug::api::UUID;
+use nmea0183::ParseResult;
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::time::Duration;
+
+// Unlike the FIT/GPX/TCX exports, these keep every single notification as its
+// own row, decoded as far as we know how, along with its raw bytes.
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Text(String),
+    Floats(Vec<f32>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Row {
+    pub elapsed: Duration,
+    pub source: String,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+// Every field that any row may have, in the order they appear as CSV columns
+const COLUMNS: [&str; 29] = [
+    "flags",
+    "instantaneous_power",
+    "pedal_power_balance_percent",
+    "accumulated_torque",
+    "accumulated_torque_source",
+    "wheel_revolution_count",
+    "wheel_event_time",
+    "crank_revolution_count",
+    "crank_event_time",
+    "instantaneous_speed",
+    "instantaneous_cadence",
+    "total_distance",
+    "resistance_level",
+    "heart_rate",
+    "sensor_contact_detected",
+    "energy_expended",
+    "rr_intervals",
+    "latitude",
+    "longitude",
+    "altitude",
+    "satellites",
+    "hdop",
+    "workout_step_index",
+    "target_power",
+    "step_duration",
+    "is_paused",
+    "has_fix",
+    "sentence",
+    "raw",
+];
+
+pub fn notification_to_row(elapsed: Duration, notification: &Notification) -> Row {
+    match notification {
+        Notification::Ble((uuid, data)) => Row {
+            elapsed,
+            source: format_uuid(uuid),
+            fields: ble_to_fields(uuid, data),
+        },
+        Notification::Gps(result) => Row {
+            elapsed,
+            source: "nmea".to_string(),
+            fields: gps_to_fields(result),
+        },
+        Notification::WorkoutStep((index, power, duration)) => {
+            let mut fields = vec![
+                ("workout_step_index", Value::Integer(*index as i64)),
+                ("target_power", Value::Integer(*power as i64)),
+            ];
+            if let Some(d) = duration {
+                fields.push(("step_duration", Value::Float(d.as_secs_f64())));
+            }
+            Row {
+                elapsed,
+                source: "workout".to_string(),
+                fields,
+            }
+        }
+        Notification::Paused(is_paused) => Row {
+            elapsed,
+            source: "workout".to_string(),
+            fields: vec![("is_paused", Value::Boolean(*is_paused))],
+        },
+    }
+}
+
+// The standard textual form, with 16-bit UUIDs expanded onto the Bluetooth
+// base UUID.  btleplug stores 128-bit UUIDs little endian.
+pub fn format_uuid(uuid: &UUID) -> String {
+    let bytes: Vec<u8> = match uuid {
+        UUID::B16(x) => {
+            let [a, b] = x.to_be_bytes();
+            vec![
+                0x00, 0x00, a, b, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5f, 0x9b, 0x34,
+                0xfb,
+            ]
+        }
+        UUID::B128(x) => x.iter().rev().cloned().collect(),
+    };
+    let mut s = String::new();
+    for (i, b) in bytes.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            s.push('-');
+        }
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+fn revolution_fields(
+    count_name: &'static str,
+    time_name: &'static str,
+    data: &Option<RevolutionData>,
+) -> Vec<(&'static str, Value)> {
+    match data {
+        Some(r) => vec![
+            (count_name, Value::Integer(r.revolution_count as i64)),
+            (time_name, Value::Float(r.last_revolution_event_time)),
+        ],
+        None => vec![],
+    }
+}
+
+fn ble_to_fields(uuid: &UUID, data: &Vec<u8>) -> Vec<(&'static str, Value)> {
+    let mut fields = Vec::new();
+    if let Some(flags) = data.first() {
+        fields.push(("flags", Value::Integer(*flags as i64)));
+    }
+
+    if *uuid == hrm::MEASURE_UUID {
+        let m = parse_hrm(data);
+        fields.push(("heart_rate", Value::Integer(m.bpm as i64)));
+        if let Some(c) = m.is_sensor_contact_detected {
+            fields.push(("sensor_contact_detected", Value::Boolean(c)));
+        }
+        if let Some(e) = m.energy_expended {
+            fields.push(("energy_expended", Value::Integer(e as i64)));
+        }
+        fields.push(("rr_intervals", Value::Floats(m.rr_intervals)));
+    } else if *uuid == kickr::MEASURE_UUID {
+        let m = parse_cycling_power_measurement(data);
+        fields.push((
+            "instantaneous_power",
+            Value::Integer(m.instantaneous_power as i64),
+        ));
+        if let Some(b) = m.pedal_power_balance_percent {
+            fields.push(("pedal_power_balance_percent", Value::Float(b as f64)));
+        }
+        if let Some((source, torque)) = m.accumulated_torque {
+            fields.push(("accumulated_torque", Value::Float(torque)));
+            let source = match source {
+                AccumulatedTorqueSource::Wheel => "wheel",
+                AccumulatedTorqueSource::Crank => "crank",
+            };
+            fields.push(("accumulated_torque_source", Value::Text(source.to_string())));
+        }
+        fields.extend(revolution_fields(
+            "wheel_revolution_count",
+            "wheel_event_time",
+            &m.wheel_revolution_data,
+        ));
+        fields.extend(revolution_fields(
+            "crank_revolution_count",
+            "crank_event_time",
+            &m.crank_revolution_data,
+        ));
+    } else if *uuid == fitness_machine::INDOOR_BIKE_DATA_UUID {
+        let m = fitness_machine::parse_indoor_bike_data(data);
+        if let Some(s) = m.instantaneous_speed {
+            fields.push(("instantaneous_speed", Value::Float(s as f64)));
+        }
+        if let Some(c) = m.instantaneous_cadence {
+            fields.push(("instantaneous_cadence", Value::Float(c as f64)));
+        }
+        if let Some(d) = m.total_distance {
+            fields.push(("total_distance", Value::Integer(d as i64)));
+        }
+        if let Some(l) = m.resistance_level {
+            fields.push(("resistance_level", Value::Integer(l as i64)));
+        }
+        if let Some(p) = m.instantaneous_power {
+            fields.push(("instantaneous_power", Value::Integer(p as i64)));
+        }
+        if let Some(h) = m.heart_rate {
+            fields.push(("heart_rate", Value::Integer(h as i64)));
+        }
+    } else if *uuid == csc_measurement::MEASURE_UUID {
+        let m = csc_measurement::parse_csc_measurement(data);
+        fields.extend(revolution_fields(
+            "wheel_revolution_count",
+            "wheel_event_time",
+            &m.wheel,
+        ));
+        fields.extend(revolution_fields(
+            "crank_revolution_count",
+            "crank_event_time",
+            &m.crank,
+        ));
+    }
+
+    let mut raw = String::new();
+    for b in data {
+        write!(raw, "{:02x}", b).unwrap();
+    }
+    fields.push(("raw", Value::Text(raw)));
+    fields
+}
+
+fn gps_to_fields(result: &ParseResult) -> Vec<(&'static str, Value)> {
+    let mut fields = Vec::new();
+    match result {
+        ParseResult::GGA(Some(gga)) => {
+            fields.push(("sentence", Value::Text("GGA".to_string())));
+            fields.push(("has_fix", Value::Boolean(true)));
+            fields.push(("latitude", Value::Float(gga.latitude.as_f64())));
+            fields.push(("longitude", Value::Float(gga.longitude.as_f64())));
+            fields.push(("altitude", Value::Float(gga.altitude.meters as f64)));
+            fields.push(("satellites", Value::Integer(gga.sat_in_use as i64)));
+            fields.push(("hdop", Value::Float(gga.hdop as f64)));
+        }
+        ParseResult::GGA(None) => {
+            fields.push(("sentence", Value::Text("GGA".to_string())));
+            fields.push(("has_fix", Value::Boolean(false)));
+        }
+        _ => {}
+    }
+    // We don't decode other sentences, but they're kept in a readable form
+    fields.push(("raw", Value::Text(format!("{:?}", result))));
+    fields
+}
+
+fn value_to_csv(value: &Value) -> String {
+    match value {
+        Value::Integer(x) => x.to_string(),
+        Value::Float(x) => x.to_string(),
+        Value::Boolean(x) => x.to_string(),
+        Value::Text(x) => {
+            if x.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+                format!("\"{}\"", x.replace('"', "\"\""))
+            } else {
+                x.clone()
+            }
+        }
+        Value::Floats(xs) => xs
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(";"),
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// JSON has no NaN or infinity, so those are written as null
+fn float_to_json<T: ToString>(x: T, is_finite: bool) -> String {
+    if is_finite {
+        x.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn value_to_json(value: &Value) -> String {
+    match value {
+        Value::Integer(x) => x.to_string(),
+        Value::Float(x) => float_to_json(x, x.is_finite()),
+        Value::Boolean(x) => x.to_string(),
+        Value::Text(x) => escape_json(x),
+        Value::Floats(xs) => format!(
+            "[{}]",
+            xs.iter()
+                .map(|x| float_to_json(x, x.is_finite()))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+    }
+}
+
+pub fn csv_header() -> String {
+    let mut s = String::from("elapsed,source");
+    for c in COLUMNS.iter() {
+        s.push(',');
+        s.push_str(c);
+    }
+    s.push('\n');
+    s
+}
+
+pub fn row_to_csv(row: &Row) -> String {
+    let mut s = format!("{},{}", row.elapsed.as_secs_f64(), row.source);
+    for c in COLUMNS.iter() {
+        s.push(',');
+        if let Some((_, v)) = row.fields.iter().find(|(name, _)| name == c) {
+            s.push_str(&value_to_csv(v));
+        }
+    }
+    s.push('\n');
+    s
+}
+
+// Fields that a row doesn't have are omitted, rather than null
+pub fn row_to_ndjson(row: &Row) -> String {
+    let mut s = format!(
+        "{{\"elapsed\":{},\"source\":{}",
+        row.elapsed.as_secs_f64(),
+        escape_json(&row.source)
+    );
+    for (name, value) in row.fields.iter() {
+        write!(s, ",\"{}\":{}", name, value_to_json(value)).unwrap();
+    }
+    s.push_str("}\n");
+    s
+}
+
+// Reads out rows as they're needed, so that a long session is streamed rather
+// than built up in memory all at once
+pub struct RowReader<I> {
+    rows: I,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl<I: Iterator<Item = String>> RowReader<I> {
+    pub fn new(rows: I) -> RowReader<I> {
+        RowReader {
+            rows,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = String>> Read for RowReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            match self.rows.next() {
+                Some(row) => {
+                    self.pending = row.into_bytes();
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len() - self.position);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        csv_header, escape_json, format_uuid, notification_to_row, row_to_csv, row_to_ndjson, Row,
+        RowReader, Value,
+    };
+    use crate::ble::fitness_machine;
+    use crate::peripherals::hrm;
+    use crate::telemetry_db::Notification;
+    use btleplug::api::UUID;
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn format_uuid_16_bit() {
+        assert_eq!(
+            format_uuid(&UUID::B16(0x2A37)),
+            "00002a37-0000-1000-8000-00805f9b34fb"
+        );
+    }
+
+    #[test]
+    fn format_uuid_128_bit() {
+        assert_eq!(
+            format_uuid(&UUID::B128([
+                0x8B, 0xEB, 0x9F, 0x0F, 0x50, 0xF1, 0xFA, 0x97, 0xB3, 0x4A, 0x7D, 0x0A, 0x05, 0xE0,
+                0x26, 0xA0,
+            ])),
+            "a026e005-0a7d-4ab3-97fa-f1500f9feb8b"
+        );
+    }
+
+    #[test]
+    fn hrm_row_keeps_rr_intervals_and_flags() {
+        let row = notification_to_row(
+            Duration::from_millis(1500),
+            &Notification::Ble((hrm::MEASURE_UUID, vec![0b10110, 70, 0, 4, 0, 2])),
+        );
+        assert_eq!(
+            row,
+            Row {
+                elapsed: Duration::from_millis(1500),
+                source: "00002a37-0000-1000-8000-00805f9b34fb".to_string(),
+                fields: vec![
+                    ("flags", Value::Integer(0b10110)),
+                    ("heart_rate", Value::Integer(70)),
+                    ("sensor_contact_detected", Value::Boolean(true)),
+                    ("rr_intervals", Value::Floats(vec![1.0, 0.5])),
+                    ("raw", Value::Text("164600040002".to_string())),
+                ],
+            }
+        );
+        assert_eq!(
+            row_to_csv(&row),
+            "1.5,00002a37-0000-1000-8000-00805f9b34fb,22,,,,,,,,,,,,,70,true,,1;0.5,,,,,,,,,,,,164600040002\n"
+        );
+        assert_eq!(
+            row_to_ndjson(&row),
+            concat!(
+                "{\"elapsed\":1.5,\"source\":\"00002a37-0000-1000-8000-00805f9b34fb\",",
+                "\"flags\":22,\"heart_rate\":70,\"sensor_contact_detected\":true,",
+                "\"rr_intervals\":[1,0.5],\"raw\":\"164600040002\"}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn indoor_bike_data_row() {
+        let row = notification_to_row(
+            Duration::from_secs(2),
+            &Notification::Ble((
+                fitness_machine::INDOOR_BIKE_DATA_UUID,
+                vec![0x44, 0x00, 0x10, 0x0e, 0xb4, 0x00, 0xfa, 0x00],
+            )),
+        );
+        assert_eq!(
+            row_to_ndjson(&row),
+            concat!(
+                "{\"elapsed\":2,\"source\":\"00002ad2-0000-1000-8000-00805f9b34fb\",",
+                "\"flags\":68,\"instantaneous_speed\":10,\"instantaneous_cadence\":90,",
+                "\"instantaneous_power\":250,\"raw\":\"4400100eb400fa00\"}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn workout_step_row() {
+        let row = notification_to_row(
+            Duration::from_secs(60),
+            &Notification::WorkoutStep((2, 300, Some(Duration::from_secs(120)))),
+        );
+        assert_eq!(
+            row_to_ndjson(&row),
+            concat!(
+                "{\"elapsed\":60,\"source\":\"workout\",",
+                "\"workout_step_index\":2,\"target_power\":300,\"step_duration\":120}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn paused_row() {
+        let row = notification_to_row(Duration::from_secs(90), &Notification::Paused(true));
+        assert_eq!(
+            row_to_ndjson(&row),
+            "{\"elapsed\":90,\"source\":\"workout\",\"is_paused\":true}\n"
+        );
+    }
+
+    #[test]
+    fn csv_header_matches_columns() {
+        assert_eq!(
+            csv_header().split(',').count(),
+            row_to_csv(&notification_to_row(
+                Duration::from_secs(0),
+                &Notification::WorkoutStep((0, 100, None)),
+            ))
+            .split(',')
+            .count()
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_are_null_in_json() {
+        let row = Row {
+            elapsed: Duration::from_secs(1),
+            source: "test".to_string(),
+            fields: vec![
+                ("instantaneous_speed", Value::Float(std::f64::NAN)),
+                ("rr_intervals", Value::Floats(vec![0.5, std::f32::INFINITY])),
+            ],
+        };
+        assert_eq!(
+            row_to_ndjson(&row),
+            concat!(
+                "{\"elapsed\":1,\"source\":\"test\",",
+                "\"instantaneous_speed\":null,\"rr_intervals\":[0.5,null]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn row_reader_reads_rows_across_small_buffers() {
+        let rows = vec!["abc".to_string(), "".to_string(), "de\n".to_string()];
+        let mut reader = RowReader::new(rows.into_iter());
+        let mut buf = [0; 2];
+        let mut read = Vec::new();
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(read, b"abcde\n");
+    }
+
+    #[test]
+    fn escape_json_control_characters() {
+        assert_eq!(escape_json("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
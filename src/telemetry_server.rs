@@ -1,15 +1,23 @@
-use crate::db_session_to_fit;
-use crate::telemetry_db::TelemetryDb;
+use crate::peripherals::sensor::ROLES;
+use crate::telemetry_db::{Profile, TelemetryDb};
+use crate::workout_dsl;
+use crate::{
+    db_session_to_csv, db_session_to_fit, db_session_to_gpx, db_session_to_ndjson,
+    db_session_to_tcx, load_library_workout, DEFAULT_FTP, WORKOUT_DIRECTORY,
+};
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{tag, take_while1},
     character::complete::digit1,
-    combinator::map,
-    sequence::{preceded, terminated},
+    combinator::{all_consuming, map, map_opt, opt},
+    sequence::{pair, preceded, terminated},
     IResult,
 };
-use std::{mem, str::FromStr, sync::Arc, thread, thread::JoinHandle, time::Duration};
-use tiny_http::{Header, Method, Response, Server, StatusCode};
+use std::io::{Cursor, Read};
+use std::{
+    fs, mem, path::Path, str::FromStr, sync::Arc, thread, thread::JoinHandle, time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
 
 pub struct TelemetryServer {
     running: Option<Arc<()>>,
@@ -33,76 +41,14 @@ impl TelemetryServer {
                             break;
                         }
                     }
-                    // TODO: Reduce this awful nesting
-                    Some(request) => {
-                        #[allow(unused_assignments)]
-                        let mut session: Vec<u8> = vec![];
+                    Some(mut request) => {
                         // TODO: Some sort of simple auth (maybe a random pin on
                         // device?)
-                        // TODO: Handle more than just the latest
-                        let response = match parse_url(request.url()) {
-                            Ok(url) => {
-                                if request.method() == &Method::Get {
-                                    let key = match url {
-                                        (_, UrlKey::Latest) => {
-                                            db.get_most_recent_session().unwrap()
-                                        }
-                                        (_, UrlKey::Key(k)) => Some(k),
-                                    };
-                                    match key {
-                                        Some(requested_session) => {
-                                            session = db_session_to_fit(&db, requested_session);
-                                            let mut r = Response::new(
-                                                StatusCode(200),
-                                                // TODO; Header for next most recent
-                                                vec![
-                                                    Header::from_bytes(
-                                                        &b"Content-Type"[..],
-                                                        &b"application/vnd.ant.fit"[..],
-                                                    )
-                                                    .unwrap(),
-                                                    Header::from_bytes(
-                                                        &b"Session-Key"[..],
-                                                        format!("{:?}", requested_session),
-                                                    )
-                                                    .unwrap(),
-                                                ],
-                                                &session[..],
-                                                None,
-                                                None,
-                                            );
-                                            if let Ok(Some(key)) =
-                                                db.get_previous_session(requested_session)
-                                            {
-                                                r.add_header(
-                                                    Header::from_bytes(
-                                                        &b"Previous-Session-Key"[..],
-                                                        format!("{:?}", key),
-                                                    )
-                                                    .unwrap(),
-                                                )
-                                            }
-                                            r
-                                        }
-                                        None => {
-                                            // The rare case where there are no
-                                            // recorded workouts yet
-                                            Response::new(
-                                                StatusCode(404),
-                                                vec![],
-                                                &[][..],
-                                                None,
-                                                None,
-                                            )
-                                        }
-                                    }
-                                } else {
-                                    Response::new(StatusCode(405), vec![], &[][..], None, None)
-                                }
-                            }
-                            Err(_) => Response::new(StatusCode(404), vec![], &[][..], None, None),
-                        };
-                        request.respond(response).unwrap();
+                        let (status, headers, body) = respond(&db, &mut request);
+                        // Without a length, the body is sent in chunks as it's read
+                        request
+                            .respond(Response::new(status, headers, body, None, None))
+                            .unwrap();
                     }
                 }
             }
@@ -124,41 +70,518 @@ impl Drop for TelemetryServer {
     }
 }
 
+// Bodies are readers, so that large exports can be streamed
+type Body<'a> = Box<dyn Read + 'a>;
+
+fn respond<'a>(db: &'a TelemetryDb, request: &mut Request) -> (StatusCode, Vec<Header>, Body<'a>) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    match (&method, parse_route(&url)) {
+        (Method::Get, Some(Route::Workout(url_key, format))) => get_workout(db, url_key, format),
+        (Method::Get, Some(Route::LibraryFit(name, rider))) => {
+            let (status, message) = get_library_fit(db, name, rider);
+            let headers = if status == StatusCode(200) {
+                vec![content_type(b"application/vnd.ant.fit")]
+            } else {
+                vec![]
+            };
+            (status, headers, bytes(message))
+        }
+        (Method::Put, Some(Route::LibraryWorkout(name))) => {
+            with_body(request, |body| put_library_workout(name, body))
+        }
+        (Method::Get, Some(Route::RiderFtp(rider))) => without_headers(get_rider_ftp(db, rider)),
+        (Method::Put, Some(Route::RiderFtp(rider))) => {
+            with_body(request, |body| put_rider_ftp(db, rider, body))
+        }
+        (Method::Get, Some(Route::RiderSensor(rider, role))) => {
+            without_headers(get_rider_sensor(db, rider, role))
+        }
+        (Method::Put, Some(Route::RiderSensor(rider, role))) => {
+            with_body(request, |body| put_rider_sensor(db, rider, role, body))
+        }
+        (Method::Delete, Some(Route::RiderSensor(rider, role))) => {
+            without_headers(delete_rider_sensor(db, rider, role))
+        }
+        (_, Some(_)) => (StatusCode(405), vec![], bytes(vec![])),
+        (_, None) => (StatusCode(404), vec![], bytes(vec![])),
+    }
+}
+
+fn content_type(value: &[u8]) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value).unwrap()
+}
+
+fn bytes<'a>(message: Vec<u8>) -> Body<'a> {
+    Box::new(Cursor::new(message))
+}
+
+fn without_headers<'a>(
+    (status, message): (StatusCode, Vec<u8>),
+) -> (StatusCode, Vec<Header>, Body<'a>) {
+    (status, vec![], bytes(message))
+}
+
+fn with_body<'a, F>(request: &mut Request, f: F) -> (StatusCode, Vec<Header>, Body<'a>)
+where
+    F: FnOnce(&str) -> (StatusCode, Vec<u8>),
+{
+    without_headers(match read_body(request.as_reader()) {
+        Ok(body) => f(&body),
+        Err(e) => e,
+    })
+}
+
+// TODO: Handle more than just the latest
+fn get_workout<'a>(
+    db: &'a TelemetryDb,
+    url_key: UrlKey,
+    format: UrlFormat,
+) -> (StatusCode, Vec<Header>, Body<'a>) {
+    let key = match url_key {
+        UrlKey::Latest => db.get_most_recent_session().unwrap(),
+        UrlKey::Key(k) => Some(k),
+    };
+    match key {
+        Some(requested_session) => {
+            let (body, mime): (Body, &[u8]) = match format {
+                UrlFormat::Fit => (
+                    bytes(db_session_to_fit(db, requested_session)),
+                    b"application/vnd.ant.fit",
+                ),
+                UrlFormat::Gpx => (
+                    bytes(db_session_to_gpx(db, requested_session)),
+                    b"application/gpx+xml",
+                ),
+                UrlFormat::Tcx => (
+                    bytes(db_session_to_tcx(db, requested_session)),
+                    b"application/vnd.garmin.tcx+xml",
+                ),
+                UrlFormat::Csv => (
+                    Box::new(db_session_to_csv(db, requested_session)),
+                    b"text/csv",
+                ),
+                UrlFormat::Ndjson => (
+                    Box::new(db_session_to_ndjson(db, requested_session)),
+                    b"application/x-ndjson",
+                ),
+            };
+            // TODO; Header for next most recent
+            let mut headers = vec![
+                content_type(mime),
+                Header::from_bytes(&b"Session-Key"[..], format!("{:?}", requested_session))
+                    .unwrap(),
+            ];
+            if let Ok(Some(key)) = db.get_previous_session(requested_session) {
+                headers.push(
+                    Header::from_bytes(&b"Previous-Session-Key"[..], format!("{:?}", key)).unwrap(),
+                )
+            }
+            (StatusCode(200), headers, body)
+        }
+        // The rare case where there are no recorded workouts yet
+        None => (StatusCode(404), vec![], bytes(vec![])),
+    }
+}
+
+// Everything the server answers to.  URLs have to match in full, so that
+// trailing garbage is never mistaken for (or written as) something else.
+#[derive(Debug, PartialEq, Eq)]
+enum Route<'a> {
+    Workout(UrlKey, UrlFormat),
+    LibraryWorkout(&'a str),
+    LibraryFit(&'a str, Option<&'a str>),
+    RiderFtp(&'a str),
+    RiderSensor(&'a str, &'a str),
+}
+
+fn parse_route(i: &str) -> Option<Route> {
+    all_consuming(alt((
+        map(parse_url, |(key, format)| Route::Workout(key, format)),
+        map_opt(
+            pair(parse_library_url, opt(parse_rider_query)),
+            |((name, format), rider)| match (format, rider) {
+                (LibraryFormat::Workout, None) => Some(Route::LibraryWorkout(name)),
+                // Only FIT workouts depend on the rider
+                (LibraryFormat::Workout, Some(_)) => None,
+                (LibraryFormat::Fit, rider) => Some(Route::LibraryFit(name, rider)),
+            },
+        ),
+        map(parse_rider_ftp_url, Route::RiderFtp),
+        map(parse_rider_sensor_url, |(rider, role)| {
+            Route::RiderSensor(rider, role)
+        }),
+    )))(i)
+    .ok()
+    .map(|(_, route)| route)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum UrlKey {
     Latest,
     Key(u64),
 }
 
-// TODO: Terminate
+#[derive(Debug, PartialEq, Eq)]
+enum UrlFormat {
+    Fit,
+    Gpx,
+    Tcx,
+    Csv,
+    Ndjson,
+}
+
 // TODO: This is a bit silly not to first put this through a standard URL parser
 // that would first break it into components (which _then_ could be more
 // thoroughly parsed).
-fn parse_url(i: &str) -> IResult<&str, UrlKey> {
-    terminated(
-        preceded(
-            tag("/workouts/"),
+fn parse_url(i: &str) -> IResult<&str, (UrlKey, UrlFormat)> {
+    preceded(
+        tag("/workouts/"),
+        pair(
             alt((
                 map(tag("latest"), |_| UrlKey::Latest),
                 map(digit1, |s| UrlKey::Key(u64::from_str(s).unwrap())),
             )),
+            alt((
+                map(tag(".fit"), |_| UrlFormat::Fit),
+                map(tag(".gpx"), |_| UrlFormat::Gpx),
+                map(tag(".tcx"), |_| UrlFormat::Tcx),
+                map(tag(".csv"), |_| UrlFormat::Csv),
+                map(tag(".ndjson"), |_| UrlFormat::Ndjson),
+            )),
+        ),
+    )(i)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum LibraryFormat {
+    // Our own text format, which is only ever written
+    Workout,
+    // A FIT workout file, for running it on other head units
+    Fit,
+}
+
+// Library workouts are named by the URL, so they're restricted to characters
+// that are safe to use as a file name.
+fn parse_library_url(i: &str) -> IResult<&str, (&str, LibraryFormat)> {
+    preceded(
+        tag("/library/"),
+        pair(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            alt((
+                map(tag(".workout"), |_| LibraryFormat::Workout),
+                map(tag(".fit"), |_| LibraryFormat::Fit),
+            )),
+        ),
+    )(i)
+}
+
+// Like /library/threshold.fit?rider=Zenia, to use that rider's FTP
+fn parse_rider_query(i: &str) -> IResult<&str, &str> {
+    preceded(
+        tag("?rider="),
+        take_while1(|c: char| c.is_ascii_alphanumeric()),
+    )(i)
+}
+
+// Bodies are small and written by hand, so anything bigger than this is refused
+// rather than read into memory
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+fn read_body(reader: &mut dyn Read) -> Result<String, (StatusCode, Vec<u8>)> {
+    let mut body = String::new();
+    match reader.take(MAX_BODY_BYTES + 1).read_to_string(&mut body) {
+        Ok(n) if n as u64 > MAX_BODY_BYTES => Err((
+            StatusCode(413),
+            format!("Bodies are limited to {} bytes\n", MAX_BODY_BYTES).into_bytes(),
+        )),
+        Ok(_) => Ok(body),
+        Err(_) => Err((StatusCode(400), vec![])),
+    }
+}
+
+// Validates and saves a workout written in our DSL, so that it's in the menu
+// the next time the device starts.  Parse errors are returned to the client.
+fn put_library_workout(name: &str, body: &str) -> (StatusCode, Vec<u8>) {
+    match workout_dsl::parse_workout(body) {
+        Ok(_) => {
+            let path = Path::new(WORKOUT_DIRECTORY).join(format!("{}.workout", name));
+            match fs::create_dir_all(WORKOUT_DIRECTORY).and_then(|_| fs::write(path, body)) {
+                Ok(()) => (StatusCode(204), vec![]),
+                Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+            }
+        }
+        Err(e) => (StatusCode(400), format!("{}\n", e).into_bytes()),
+    }
+}
+
+// Encodes any workout in our library (whatever its format) as a FIT workout.
+// Percent of FTP targets are resolved against the rider's FTP, if they have
+// one.
+fn get_library_fit(db: &TelemetryDb, name: &str, rider: Option<&str>) -> (StatusCode, Vec<u8>) {
+    let ftp = match rider.map(|r| db.get_profile(r)) {
+        Some(Ok(Some(profile))) => profile.ftp,
+        Some(Err(e)) => return (StatusCode(500), format!("{}\n", e).into_bytes()),
+        _ => DEFAULT_FTP,
+    };
+    match load_library_workout(name) {
+        Some(Ok((title, workout))) => (StatusCode(200), workout.to_fit_file(&title, ftp)),
+        Some(Err(e)) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+        None => (StatusCode(404), vec![]),
+    }
+}
+
+// Rider names are the ones from the menu, which are all plain words
+fn parse_rider_ftp_url(i: &str) -> IResult<&str, &str> {
+    preceded(
+        tag("/riders/"),
+        terminated(
+            take_while1(|c: char| c.is_ascii_alphanumeric()),
+            tag("/ftp"),
+        ),
+    )(i)
+}
+
+fn get_rider_ftp(db: &TelemetryDb, rider: &str) -> (StatusCode, Vec<u8>) {
+    match db.get_profile(rider) {
+        Ok(Some(profile)) => (StatusCode(200), format!("{}\n", profile.ftp).into_bytes()),
+        Ok(None) => (StatusCode(404), vec![]),
+        Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+    }
+}
+
+// Sets the FTP (in Watts) used to resolve a rider's percent of FTP workouts
+fn put_rider_ftp(db: &TelemetryDb, rider: &str, body: &str) -> (StatusCode, Vec<u8>) {
+    match body.trim().parse::<u16>() {
+        Ok(ftp) if ftp > 0 => match db.set_profile(rider, &Profile { ftp }) {
+            Ok(()) => (StatusCode(204), vec![]),
+            Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+        },
+        _ => (
+            StatusCode(400),
+            b"Expected a positive whole number of Watts\n".to_vec(),
+        ),
+    }
+}
+
+// Sensors are paired by their role, like /riders/Zenia/sensors/hrm
+fn parse_rider_sensor_url(i: &str) -> IResult<&str, (&str, &str)> {
+    preceded(
+        tag("/riders/"),
+        pair(
+            terminated(
+                take_while1(|c: char| c.is_ascii_alphanumeric()),
+                tag("/sensors/"),
+            ),
+            take_while1(|c: char| c.is_ascii_alphanumeric()),
         ),
-        tag(".fit"),
     )(i)
 }
 
+// Addresses are six hex bytes separated by colons, which we store the way
+// Bluetooth reports them (upper case)
+fn parse_address(s: &str) -> Option<String> {
+    let octets: Vec<&str> = s.trim().split(':').collect();
+    if octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        Some(octets.join(":").to_ascii_uppercase())
+    } else {
+        None
+    }
+}
+
+fn get_rider_sensor(db: &TelemetryDb, rider: &str, role: &str) -> (StatusCode, Vec<u8>) {
+    if !ROLES.contains(&role) {
+        return (StatusCode(404), vec![]);
+    }
+    match db.get_pairing(rider, role) {
+        Ok(Some(address)) => (StatusCode(200), format!("{}\n", address).into_bytes()),
+        Ok(None) => (StatusCode(404), vec![]),
+        Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+    }
+}
+
+// Pairs the sensor at an address, so it's used for this rider's rides instead
+// of whichever one is found first
+fn put_rider_sensor(
+    db: &TelemetryDb,
+    rider: &str,
+    role: &str,
+    body: &str,
+) -> (StatusCode, Vec<u8>) {
+    if !ROLES.contains(&role) {
+        return (StatusCode(404), vec![]);
+    }
+    match parse_address(body) {
+        Some(address) => match db.set_pairing(rider, role, &address) {
+            Ok(()) => (StatusCode(204), vec![]),
+            Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+        },
+        None => (
+            StatusCode(400),
+            b"Expected an address like AA:BB:CC:DD:EE:FF\n".to_vec(),
+        ),
+    }
+}
+
+fn delete_rider_sensor(db: &TelemetryDb, rider: &str, role: &str) -> (StatusCode, Vec<u8>) {
+    if !ROLES.contains(&role) {
+        return (StatusCode(404), vec![]);
+    }
+    match db.remove_pairing(rider, role) {
+        Ok(()) => (StatusCode(204), vec![]),
+        Err(e) => (StatusCode(500), format!("{}\n", e).into_bytes()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::parse_address;
+    use super::parse_library_url;
+    use super::parse_rider_ftp_url;
+    use super::parse_rider_query;
+    use super::parse_rider_sensor_url;
+    use super::parse_route;
     use super::parse_url;
+    use super::LibraryFormat;
+    use super::Route;
+    use super::UrlFormat;
     use super::UrlKey;
 
     #[test]
     fn parse_url_latest() {
-        assert_eq!(parse_url("/workouts/latest.fit"), Ok(("", UrlKey::Latest)))
+        assert_eq!(
+            parse_url("/workouts/latest.fit"),
+            Ok(("", (UrlKey::Latest, UrlFormat::Fit)))
+        )
     }
 
     #[test]
     fn parse_url_key() {
-        assert_eq!(parse_url("/workouts/1234.fit"), Ok(("", UrlKey::Key(1234))))
+        assert_eq!(
+            parse_url("/workouts/1234.fit"),
+            Ok(("", (UrlKey::Key(1234), UrlFormat::Fit)))
+        )
+    }
+
+    #[test]
+    fn parse_url_gpx() {
+        assert_eq!(
+            parse_url("/workouts/1234.gpx"),
+            Ok(("", (UrlKey::Key(1234), UrlFormat::Gpx)))
+        )
+    }
+
+    #[test]
+    fn parse_url_tcx() {
+        assert_eq!(
+            parse_url("/workouts/latest.tcx"),
+            Ok(("", (UrlKey::Latest, UrlFormat::Tcx)))
+        )
+    }
+
+    #[test]
+    fn parse_url_csv() {
+        assert_eq!(
+            parse_url("/workouts/1234.csv"),
+            Ok(("", (UrlKey::Key(1234), UrlFormat::Csv)))
+        )
+    }
+
+    #[test]
+    fn parse_url_ndjson() {
+        assert_eq!(
+            parse_url("/workouts/latest.ndjson"),
+            Ok(("", (UrlKey::Latest, UrlFormat::Ndjson)))
+        )
+    }
+
+    #[test]
+    fn parse_library_url_name() {
+        assert_eq!(
+            parse_library_url("/library/over-unders_2.workout"),
+            Ok(("", ("over-unders_2", LibraryFormat::Workout)))
+        )
+    }
+
+    #[test]
+    fn parse_library_url_fit_for_rider() {
+        let (rest, url) = parse_library_url("/library/threshold.fit?rider=Zenia").unwrap();
+        assert_eq!(url, ("threshold", LibraryFormat::Fit));
+        assert_eq!(parse_rider_query(rest), Ok(("", "Zenia")));
+    }
+
+    #[test]
+    fn parse_library_url_rejects_paths() {
+        assert!(parse_library_url("/library/../secrets.workout").is_err())
+    }
+
+    #[test]
+    fn parse_rider_ftp_url_name() {
+        assert_eq!(parse_rider_ftp_url("/riders/Zenia/ftp"), Ok(("", "Zenia")))
+    }
+
+    #[test]
+    fn parse_rider_ftp_url_rejects_paths() {
+        assert!(parse_rider_ftp_url("/riders/../ftp").is_err())
+    }
+
+    #[test]
+    fn parse_rider_sensor_url_name_and_role() {
+        assert_eq!(
+            parse_rider_sensor_url("/riders/Zenia/sensors/hrm"),
+            Ok(("", ("Zenia", "hrm")))
+        )
+    }
+
+    #[test]
+    fn parse_rider_sensor_url_rejects_paths() {
+        assert!(parse_rider_sensor_url("/riders/Zenia/sensors/../ftp").is_err())
+    }
+
+    #[test]
+    fn parse_route_matches_whole_urls() {
+        assert_eq!(
+            parse_route("/workouts/latest.csv"),
+            Some(Route::Workout(UrlKey::Latest, UrlFormat::Csv))
+        );
+        assert_eq!(
+            parse_route("/library/threshold.fit?rider=Zenia"),
+            Some(Route::LibraryFit("threshold", Some("Zenia")))
+        );
+        assert_eq!(
+            parse_route("/library/threshold.workout"),
+            Some(Route::LibraryWorkout("threshold"))
+        );
+        assert_eq!(
+            parse_route("/riders/Zenia/sensors/hrm"),
+            Some(Route::RiderSensor("Zenia", "hrm"))
+        );
+    }
+
+    #[test]
+    fn parse_route_rejects_trailing_garbage() {
+        assert_eq!(parse_route("/workouts/1234.fitx"), None);
+        assert_eq!(parse_route("/library/threshold.workout?rider=Zenia"), None);
+        assert_eq!(parse_route("/library/threshold.workout/../x"), None);
+        assert_eq!(parse_route("/riders/Zenia/ftp/extra"), None);
+        assert_eq!(parse_route("/riders/Zenia/sensors/hrm/../power"), None);
+    }
+
+    #[test]
+    fn parse_address_normalizes_case() {
+        assert_eq!(
+            parse_address("c4:7c:8d:6a:1b:02\n"),
+            Some("C4:7C:8D:6A:1B:02".to_string())
+        )
+    }
+
+    #[test]
+    fn parse_address_rejects_names() {
+        assert_eq!(parse_address("Polar H10"), None);
+        assert_eq!(parse_address("C4:7C:8D:6A:1B"), None);
     }
 }
@@ -1,6 +1,13 @@
+use chrono::{SecondsFormat, TimeZone, Utc};
+
 pub fn lift_a2_option<A, B, C, F: Fn(A, B) -> C>(a: Option<A>, b: Option<B>, f: F) -> Option<C> {
     match (a, b) {
         (Some(a), Some(b)) => Some(f(a, b)),
         _ => None,
     }
 }
+
+pub fn to_rfc3339(seconds_since_unix_epoch: u32) -> String {
+    Utc.timestamp(seconds_since_unix_epoch as i64, 0)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
@@ -0,0 +1,157 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    thread,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// Each gear is a simulated grade (in percent), so harder gears feel like
+// riding up a steeper hill, and easier ones like rolling downhill.
+const GRADES: [f32; 11] = [-5.0, -4.0, -3.0, -2.0, -1.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+
+// Or each gear is a percent of the trainer's maximum resistance, which feels
+// the same at any speed.  Anything past half is more of a wall than a gear.
+const RESISTANCES: [f32; 11] = [
+    0.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0, 45.0, 50.0,
+];
+
+// What the gears change on the trainer
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Gearing {
+    Grade,
+    Resistance,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VirtualGears {
+    gearing: Gearing,
+    // An index into the gearing's levels
+    gear: usize,
+}
+
+impl VirtualGears {
+    // We start in the middle, which is on the flat for grades
+    pub fn new(gearing: Gearing) -> VirtualGears {
+        VirtualGears {
+            gearing,
+            gear: GRADES.len() / 2,
+        }
+    }
+
+    fn levels(&self) -> &'static [f32; 11] {
+        match self.gearing {
+            Gearing::Grade => &GRADES,
+            Gearing::Resistance => &RESISTANCES,
+        }
+    }
+
+    // Gears are numbered from 1, like on a shifter
+    pub fn gear(&self) -> usize {
+        self.gear + 1
+    }
+
+    pub fn gear_count(&self) -> usize {
+        self.levels().len()
+    }
+
+    // The grade or resistance for the current gear, both in percent
+    pub fn level(&self) -> f32 {
+        self.levels()[self.gear]
+    }
+
+    // Shifting past either end does nothing
+    pub fn shift_up(&mut self) {
+        self.gear = std::cmp::min(self.gear + 1, self.levels().len() - 1);
+    }
+
+    pub fn shift_down(&mut self) {
+        self.gear = self.gear.saturating_sub(1);
+    }
+}
+
+pub struct VirtualGearsHandle {
+    running: Arc<Mutex<bool>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl VirtualGearsHandle {
+    pub fn exit(&mut self) {
+        {
+            let mut running = self.running.lock().unwrap();
+            *running = false;
+        }
+        if let Some(jh) = mem::replace(&mut self.join_handle, None) {
+            jh.join().unwrap();
+        }
+    }
+}
+
+// Calls set_level with the gear's level whenever the gear changes (and once to
+// start), until it returns that the level was applied.  Setting the level can
+// be slow, so this happens on its own thread, rather than in whatever shifts
+// the gears.
+pub fn run<F>(gears: Arc<Mutex<VirtualGears>>, set_level: F) -> VirtualGearsHandle
+where
+    F: Fn(f32) -> bool + Send + 'static,
+{
+    let running = Arc::new(Mutex::new(true));
+    let running_for_thread = running.clone();
+    let join_handle = Some(thread::spawn(move || {
+        let mut last_gear = None;
+        loop {
+            {
+                if !*running_for_thread.lock().unwrap() {
+                    break;
+                }
+            }
+            let current = *gears.lock().unwrap();
+            if last_gear != Some(current) && set_level(current.level()) {
+                last_gear = Some(current);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }));
+
+    VirtualGearsHandle {
+        running,
+        join_handle,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gearing, VirtualGears};
+
+    #[test]
+    fn new_starts_flat() {
+        let gears = VirtualGears::new(Gearing::Grade);
+        assert_eq!(gears.gear(), 6);
+        assert_eq!(gears.level(), 0.0);
+    }
+
+    #[test]
+    fn shifting_stops_at_the_ends() {
+        let mut gears = VirtualGears::new(Gearing::Grade);
+        for _ in 0..10 {
+            gears.shift_up();
+        }
+        assert_eq!((gears.gear(), gears.level()), (11, 5.0));
+        for _ in 0..20 {
+            gears.shift_down();
+        }
+        assert_eq!((gears.gear(), gears.level()), (1, -5.0));
+    }
+
+    #[test]
+    fn resistance_gears() {
+        let mut gears = VirtualGears::new(Gearing::Resistance);
+        assert_eq!((gears.gear(), gears.level()), (6, 25.0));
+        gears.shift_down();
+        assert_eq!((gears.gear(), gears.level()), (5, 20.0));
+        for _ in 0..20 {
+            gears.shift_up();
+        }
+        assert_eq!((gears.gear(), gears.level()), (11, 50.0));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::cycle_tree::CycleTree;
+use crate::fit;
 use std::{
+    convert::TryFrom,
     mem,
     sync::{Arc, Mutex},
     thread,
@@ -7,22 +9,208 @@ use std::{
     time::{Duration, Instant},
 };
 
+// Power is either absolute, or relative to the rider's FTP, which isn't known
+// until the workout starts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Power {
+    Watts(u16),
+    FtpPercent(f32),
+}
+
+impl Power {
+    pub fn resolve(&self, ftp: u16) -> u16 {
+        match self {
+            Power::Watts(w) => *w,
+            Power::FtpPercent(p) => (*p as f64 * ftp as f64 / 100.0).round() as u16,
+        }
+    }
+}
+
+// What a step asks of the trainer over its duration, in either Power or
+// resolved Watts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Target<P> {
+    // Sets the power
+    Steady(P),
+    // Changes linearly from the first power to the second
+    Ramp(P, P),
+    // Relative to the power of the step before (or 0 for the first), which
+    // makes it easy to build ladders by repeating them
+    Add(P),
+    Sub(P),
+}
+
+impl<P> Target<P> {
+    fn is_relative(&self) -> bool {
+        match self {
+            Target::Add(_) | Target::Sub(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Target<Power> {
+    fn resolve(&self, ftp: u16) -> Target<u16> {
+        match self {
+            Target::Steady(p) => Target::Steady(p.resolve(ftp)),
+            Target::Ramp(from, to) => Target::Ramp(from.resolve(ftp), to.resolve(ftp)),
+            Target::Add(p) => Target::Add(p.resolve(ftp)),
+            Target::Sub(p) => Target::Sub(p.resolve(ftp)),
+        }
+    }
+}
+
+impl Target<u16> {
+    // The power at some point into a step of this duration, where current is
+    // the power at the end of the step before.
+    fn at(&self, current: u16, elapsed: Duration, duration: Duration) -> u16 {
+        match self {
+            Target::Steady(p) => *p,
+            Target::Add(p) => current.saturating_add(*p),
+            Target::Sub(p) => current.saturating_sub(*p),
+            Target::Ramp(from, to) => {
+                let fraction = if duration > Duration::from_secs(0) {
+                    (elapsed.as_secs_f64() / duration.as_secs_f64()).min(1.0)
+                } else {
+                    1.0
+                };
+                (*from as f64 + (*to as f64 - *from as f64) * fraction).round() as u16
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Workout {
-    ct: CycleTree<(Duration, u16)>,
-    tail: Option<u16>,
+    ct: CycleTree<(Duration, Target<Power>)>,
+    tail: Option<Power>,
+}
+
+// The workout's own clock, which only runs while the workout isn't paused, and
+// can be moved along by skipping or extending steps.
+struct Schedule {
+    running: bool,
+    // The clock's time when it was last paused or resumed
+    clock: Duration,
+    // When the clock was last resumed, None while paused
+    resumed: Option<Instant>,
+    skip: bool,
+    extensions: u32,
+    progress: Option<WorkoutProgress>,
+}
+
+// Where a running workout is up to, so it can be shown to the rider
+#[derive(Debug, PartialEq, Clone)]
+pub struct WorkoutProgress {
+    // The index of the current step, which is step_count for the tail
+    pub step: usize,
+    pub step_count: usize,
+    // What the current step is asking for right now (even while paused)
+    pub target: u16,
+    // None for the tail, which never ends
+    pub remaining: Option<Duration>,
+    // None while in the tail
+    pub next_target: Option<u16>,
+    pub elapsed: Duration,
+    // Everything but the tail, including any extensions
+    pub duration: Duration,
+    pub is_paused: bool,
+    // Whether the trainer has acknowledged what we're asking it for
+    pub is_applied: bool,
+}
+
+impl WorkoutProgress {
+    // How far through the workout we are, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        if self.duration > Duration::from_secs(0) {
+            (self.elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+// Everything that depends on the time is given now, rather than reading the
+// real clock, so the schedule can be tested without waiting on it.
+impl Schedule {
+    fn new(start: Instant) -> Schedule {
+        Schedule {
+            running: true,
+            clock: Duration::from_secs(0),
+            resumed: Some(start),
+            skip: false,
+            extensions: 0,
+            progress: None,
+        }
+    }
+
+    fn elapsed(&self, now: Instant) -> Duration {
+        self.clock
+            + self
+                .resumed
+                .map_or(Duration::from_secs(0), |r| now.saturating_duration_since(r))
+    }
+
+    fn set_elapsed(&mut self, elapsed: Duration, now: Instant) {
+        self.clock = elapsed;
+        if self.resumed.is_some() {
+            self.resumed = Some(now);
+        }
+    }
+
+    fn pause(&mut self, now: Instant) {
+        if self.resumed.is_some() {
+            self.clock = self.elapsed(now);
+            self.resumed = None;
+        }
+    }
+
+    fn resume(&mut self, now: Instant) {
+        if self.resumed.is_none() {
+            self.resumed = Some(now);
+        }
+    }
 }
 
 pub struct WorkoutHandle {
-    running: Arc<Mutex<bool>>,
+    schedule: Arc<Mutex<Schedule>>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl WorkoutHandle {
+    // Holds the clock and drops to recovery power until resumed
+    pub fn pause(&self) {
+        self.schedule.lock().unwrap().pause(Instant::now());
+    }
+
+    pub fn resume(&self) {
+        self.schedule.lock().unwrap().resume(Instant::now());
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.schedule.lock().unwrap().resumed.is_none()
+    }
+
+    // None until the first step starts
+    pub fn progress(&self) -> Option<WorkoutProgress> {
+        self.schedule.lock().unwrap().progress.clone()
+    }
+
+    // Moves on to the next step (the tail can't be skipped)
+    pub fn skip(&self) {
+        self.schedule.lock().unwrap().skip = true;
+    }
+
+    // The current step lasts another EXTENSION, and every step after it starts
+    // that much later (the tail is already indefinite)
+    pub fn extend(&self) {
+        self.schedule.lock().unwrap().extensions += 1;
+    }
+
     pub fn exit(&mut self) {
         {
-            let mut running = self.running.lock().unwrap();
-            *running = false;
+            let mut schedule = self.schedule.lock().unwrap();
+            schedule.running = false;
         }
         if let Some(jh) = mem::replace(&mut self.join_handle, None) {
             jh.join().unwrap();
@@ -34,58 +222,373 @@ impl Workout {
     // A workout is constructed from a cycle tree that holds how long a certain
     // amount of power should be held for, and then optionally a final power
     // that is held indefinitely at the end of the workout (defaults to 0).
-    pub fn new(ct: CycleTree<(Duration, u16)>, tail: Option<u16>) -> Workout {
+    pub fn new(ct: CycleTree<(Duration, Target<Power>)>, tail: Option<Power>) -> Workout {
         Workout { ct, tail }
     }
 
+    // Turns all relative power into absolute power for a rider with this FTP
+    fn resolve(&self, ftp: u16) -> (CycleTree<(Duration, Target<u16>)>, Option<u16>) {
+        (
+            self.ct.map(&|(d, t)| (*d, t.resolve(ftp))),
+            self.tail.map(|p| p.resolve(ftp)),
+        )
+    }
+
+    // Flattens our tree into FIT workout steps for a rider with this FTP.
+    // Nodes that repeat more than once are followed by a repeat step, and the
+    // tail becomes an open ended step.
+    pub fn to_fit_steps(&self, ftp: u16) -> Vec<fit::FitWorkoutStep> {
+        let (ct, tail) = self.resolve(ftp);
+        let mut steps = Vec::new();
+        push_fit_steps(&ct, &mut steps, &mut 0);
+        if let Some(power) = tail {
+            steps.push(fit::FitWorkoutStep::Power(power, None));
+        }
+        steps
+    }
+
+    // Encodes this workout so it can be run on other head units
+    pub fn to_fit_file(&self, name: &str, ftp: u16) -> Vec<u8> {
+        fit::to_workout_file(name, &self.to_fit_steps(ftp))
+    }
+
     // This also eventually self-corrects any drift, because we always target the
-    // correct total time for our changes.
-    pub fn run<F: Fn(u16) + Send + 'static>(self, start: Instant, set_power: F) -> WorkoutHandle {
-        // TODO: There must be a more elegant way to do this
-        let running = Arc::new(Mutex::new(true));
-        let running_for_thread = running.clone();
-        let Workout { ct, tail } = self;
+    // correct total time for our changes.  Each step that actually runs is
+    // reported to on_step (with its index, power, and duration) just before
+    // its power is set, so that intervals can be reconstructed later.  Ramps
+    // report their average power.  The tail is reported with the index after
+    // the last step and no duration.  Relative power is resolved against the
+    // given FTP before we begin.  The schedule starts from start, but the
+    // returned handle can pause, skip or extend it as we go.  set_power returns
+    // whether the trainer actually applied the power, and if it didn't, we
+    // try again after a moment.
+    pub fn run<F, G>(self, ftp: u16, start: Instant, set_power: F, on_step: G) -> WorkoutHandle
+    where
+        F: Fn(u16) -> bool + Send + 'static,
+        G: Fn(usize, u16, Option<Duration>) + Send + 'static,
+    {
+        let schedule = Arc::new(Mutex::new(Schedule::new(start)));
+        let schedule_for_thread = schedule.clone();
+        let mut runner = Runner::new(self, ftp);
         let join_handle = Some(thread::spawn(move || {
-            let mut d = Duration::from_secs(0);
-            for (wait, power) in ct.into_iter() {
-                // Overflow is not a consideration for the timeline of a single workout
-                d = d.checked_add(wait).unwrap();
-                let e = start.elapsed();
-                // If duration is negative, we continue on.
-                if let Some(_) = d.checked_sub(e) {
-                    set_power(power);
-
-                    // We loop and check every 50ms if we should move to the
-                    // next power or if the workout is teriminated.
-                    let terminate = loop {
-                        thread::sleep(Duration::from_millis(50));
-                        {
-                            if !*running_for_thread.lock().unwrap() {
-                                break true;
-                            }
-                        }
-                        if let None = d.checked_sub(start.elapsed()) {
-                            break false;
-                        }
-                    };
-                    if terminate {
-                        break;
-                    }
-                }
+            // We check every 50ms if we should move to the next power or if
+            // the workout is teriminated
+            while runner.tick(&schedule_for_thread, Instant::now(), &set_power, &on_step) {
+                thread::sleep(Duration::from_millis(50));
             }
-            set_power(tail.unwrap_or(0));
         }));
 
         WorkoutHandle {
             join_handle,
-            running,
+            schedule,
+        }
+    }
+}
+
+// The state of a running workout's thread: which step it's on, and what it
+// last asked the trainer for.  Each tick moves it along to the given time.
+struct Runner {
+    // The tail is just a step that never ends
+    steps: Vec<(Option<Duration>, Target<u16>)>,
+    tail_power: u16,
+    recovery_power: u16,
+    // Everything but the tail, including any extensions
+    duration: Duration,
+    // The index of the next step to start
+    next: usize,
+    // When the current step ends (and so the next starts) on the workout's
+    // clock
+    end: Duration,
+    // The power at the end of the current step, which relative steps build on
+    current: u16,
+    step: Option<RunningStep>,
+}
+
+struct RunningStep {
+    index: usize,
+    start: Duration,
+    // None for the tail
+    wait: Option<Duration>,
+    target: Target<u16>,
+    // The power at the end of the step before
+    previous: u16,
+    next_target: Option<u16>,
+    // When we last set the power, to what, whether we were paused, and whether
+    // the trainer applied it
+    last_set: Option<(Instant, u16, bool, bool)>,
+}
+
+impl Runner {
+    // Relative power is resolved against the given FTP before we begin
+    fn new(workout: Workout, ftp: u16) -> Runner {
+        let (ct, tail) = workout.resolve(ftp);
+        let tail_power = tail.unwrap_or(0);
+        let steps: Vec<(Option<Duration>, Target<u16>)> = ct
+            .into_iter()
+            .map(|(wait, target)| (Some(wait), target))
+            .chain(std::iter::once((None, Target::Steady(tail_power))))
+            .collect();
+        let duration = steps.iter().filter_map(|(w, _)| *w).sum();
+        Runner {
+            steps,
+            tail_power,
+            recovery_power: RECOVERY_POWER.resolve(ftp),
+            duration,
+            next: 0,
+            end: Duration::from_secs(0),
+            current: 0,
+            step: None,
+        }
+    }
+
+    // Starts the first step that isn't already over at elapsed.  The tail
+    // never ends, so there's always one to start.
+    fn start_next_step<G>(&mut self, elapsed: Duration, on_step: &G)
+    where
+        G: Fn(usize, u16, Option<Duration>),
+    {
+        loop {
+            let index = self.next;
+            self.next += 1;
+            let (wait, target) = self.steps[index];
+            let start = self.end;
+            // Skipped steps still count towards relative power
+            let previous = self.current;
+            if let Some(w) = wait {
+                self.current = target.at(previous, w, w);
+                // Overflow is not a consideration for the timeline of a
+                // single workout
+                self.end = self.end.checked_add(w).unwrap();
+                // If this step is already over, we continue on.
+                if elapsed > self.end {
+                    continue;
+                }
+            }
+            let w = wait.unwrap_or(Duration::from_secs(0));
+            on_step(index, target.at(previous, w / 2, w), wait);
+            let current = self.current;
+            let next_target = self.steps.get(index + 1).map(|(w, t)| {
+                t.at(
+                    current,
+                    Duration::from_secs(0),
+                    w.unwrap_or(Duration::from_secs(0)),
+                )
+            });
+            self.step = Some(RunningStep {
+                index,
+                start,
+                wait,
+                target,
+                previous,
+                next_target,
+                last_set: None,
+            });
+            return;
+        }
+    }
+
+    // Moves on to whichever step is running at now, and sets its power if
+    // that's due.  Ramps change their set point as they go, but no faster than
+    // the trainer can sensibly follow.  Pausing and resuming take effect right
+    // away.  Once the workout is exited, this sets the tail power and returns
+    // false.  The schedule is never locked while calling set_power or on_step.
+    fn tick<F, G>(
+        &mut self,
+        schedule: &Mutex<Schedule>,
+        now: Instant,
+        set_power: &F,
+        on_step: &G,
+    ) -> bool
+    where
+        F: Fn(u16) -> bool,
+        G: Fn(usize, u16, Option<Duration>),
+    {
+        loop {
+            if self.step.is_none() {
+                let elapsed = schedule.lock().unwrap().elapsed(now);
+                self.start_next_step(elapsed, on_step);
+            }
+            let step = self.step.as_mut().unwrap();
+            let (elapsed, is_paused, power) = {
+                let mut schedule = schedule.lock().unwrap();
+                if !schedule.running {
+                    set_power(self.tail_power);
+                    return false;
+                }
+                if let Some(w) = step.wait.as_mut() {
+                    let extension = EXTENSION * schedule.extensions;
+                    *w += extension;
+                    self.end += extension;
+                    self.duration += extension;
+                    if schedule.skip {
+                        schedule.set_elapsed(self.end, now);
+                    }
+                }
+                schedule.extensions = 0;
+                schedule.skip = false;
+
+                let end = self.end;
+                let elapsed = schedule.elapsed(now);
+                let is_paused = schedule.resumed.is_none();
+                let into_step = elapsed
+                    .checked_sub(step.start)
+                    .unwrap_or(Duration::from_secs(0));
+                let step_power = step.target.at(
+                    step.previous,
+                    into_step,
+                    step.wait.unwrap_or(Duration::from_secs(0)),
+                );
+                let power = if is_paused {
+                    self.recovery_power
+                } else {
+                    step_power
+                };
+                schedule.progress = Some(WorkoutProgress {
+                    step: step.index,
+                    step_count: self.steps.len() - 1,
+                    target: step_power,
+                    remaining: step
+                        .wait
+                        .map(|_| end.checked_sub(elapsed).unwrap_or(Duration::from_secs(0))),
+                    next_target: step.next_target,
+                    elapsed,
+                    duration: self.duration,
+                    is_paused,
+                    is_applied: step
+                        .last_set
+                        .map_or(false, |(_, p, _, is_applied)| is_applied && p == power),
+                });
+                (elapsed, is_paused, power)
+            };
+            if step.wait.is_some() && elapsed >= self.end {
+                self.step = None;
+                continue;
+            }
+
+            let is_due = step
+                .last_set
+                .map_or(true, |(t, p, was_paused, is_applied)| {
+                    was_paused != is_paused
+                        || ((p != power || !is_applied)
+                            && now.saturating_duration_since(t) >= SET_POINT_INTERVAL)
+                });
+            if is_due {
+                let is_applied = set_power(power);
+                step.last_set = Some((now, power, is_paused, is_applied));
+            }
+            return true;
+        }
+    }
+}
+
+// What the trainer holds while a workout is paused
+const RECOVERY_POWER: Power = Power::FtpPercent(40.0);
+
+// How much longer a step lasts each time it's extended
+const EXTENSION: Duration = Duration::from_secs(60);
+
+// FIT workouts have no ramps, so they're approximated by holding a constant
+// power for each step of this length (or less, for the last one), targeting
+// the ramp's power at the middle of that step.
+const RAMP_STEP: Duration = Duration::from_secs(10);
+
+// The shortest time between set points while running a ramp
+const SET_POINT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Repeats are expanded when a workout is run or exported, so anything past
+// these is a broken (or hostile) file rather than a hard workout.
+pub const MAX_REPEATS: usize = 1000;
+pub const MAX_STEPS: usize = 10_000;
+pub const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How many steps a tree expands to and how long they take, or None if either
+// overflows
+fn expanded_size(ct: &CycleTree<(Duration, Target<Power>)>) -> Option<(usize, Duration)> {
+    match ct {
+        CycleTree::Leaf((d, _)) => Some((1, *d)),
+        CycleTree::Node((count, children)) => {
+            let (steps, duration) = children.iter().try_fold(
+                (0usize, Duration::from_secs(0)),
+                |(steps, duration), child| {
+                    let (child_steps, child_duration) = expanded_size(child)?;
+                    Some((
+                        steps.checked_add(child_steps)?,
+                        duration.checked_add(child_duration)?,
+                    ))
+                },
+            )?;
+            Some((
+                steps.checked_mul(*count)?,
+                duration.checked_mul(u32::try_from(*count).ok()?)?,
+            ))
+        }
+    }
+}
+
+// Whether a parsed workout is small enough to safely run or export
+pub fn is_within_limits(ct: &CycleTree<(Duration, Target<Power>)>) -> bool {
+    expanded_size(ct).map_or(false, |(steps, duration)| {
+        steps <= MAX_STEPS && duration <= MAX_DURATION
+    })
+}
+
+fn has_relative(ct: &CycleTree<(Duration, Target<u16>)>) -> bool {
+    match ct {
+        CycleTree::Leaf((_, target)) => target.is_relative(),
+        CycleTree::Node((_, children)) => children.iter().any(has_relative),
+    }
+}
+
+// Current is the power at the end of the last step pushed, so relative steps
+// can be made absolute.
+fn push_fit_steps(
+    ct: &CycleTree<(Duration, Target<u16>)>,
+    steps: &mut Vec<fit::FitWorkoutStep>,
+    current: &mut u16,
+) {
+    match ct {
+        CycleTree::Leaf((d, Target::Ramp(from, to))) => {
+            let target = Target::Ramp(*from, *to);
+            let mut elapsed = Duration::from_secs(0);
+            while elapsed < *d {
+                let step = std::cmp::min(RAMP_STEP, *d - elapsed);
+                let power = target.at(*current, elapsed + step / 2, *d);
+                steps.push(fit::FitWorkoutStep::Power(power, Some(step)));
+                elapsed += step;
+            }
+            *current = *to;
+        }
+        CycleTree::Leaf((d, target)) => {
+            *current = target.at(*current, *d, *d);
+            steps.push(fit::FitWorkoutStep::Power(*current, Some(*d)))
+        }
+        // Each time through is different, so FIT can't repeat it for us
+        CycleTree::Node((count, children)) if *count > 1 && has_relative(ct) => {
+            for _ in 0..*count {
+                for child in children {
+                    push_fit_steps(child, steps, current);
+                }
+            }
+        }
+        CycleTree::Node((count, children)) => {
+            let from = steps.len();
+            let before = *current;
+            for child in children {
+                push_fit_steps(child, steps, current);
+            }
+            if *count == 0 {
+                steps.truncate(from);
+                *current = before;
+            } else if *count > 1 && steps.len() > from {
+                steps.push(fit::FitWorkoutStep::Repeat(from as u16, *count as u32));
+            }
         }
     }
 }
 
 #[allow(dead_code)]
 // No repetitions, just set the final indefinite power
-pub fn single_value(power: u16) -> Workout {
+pub fn single_value(power: Power) -> Workout {
     Workout::new(CycleTree::Node((0, vec![])), Some(power))
 }
 
@@ -96,15 +599,21 @@ pub fn interval_example() -> Workout {
         CycleTree::Node((
             1,
             vec![
-                CycleTree::Leaf((Duration::from_secs(300), 80)),
+                CycleTree::Leaf((Duration::from_secs(300), Target::Steady(Power::Watts(80)))),
                 CycleTree::Node((
                     5,
                     vec![
-                        CycleTree::Leaf((Duration::from_secs(180), 160)),
-                        CycleTree::Leaf((Duration::from_secs(60), 80)),
+                        CycleTree::Leaf((
+                            Duration::from_secs(180),
+                            Target::Steady(Power::Watts(160)),
+                        )),
+                        CycleTree::Leaf((
+                            Duration::from_secs(60),
+                            Target::Steady(Power::Watts(80)),
+                        )),
                     ],
                 )),
-                CycleTree::Leaf((Duration::from_secs(300), 80)),
+                CycleTree::Leaf((Duration::from_secs(300), Target::Steady(Power::Watts(80)))),
             ],
         )),
         None,
@@ -115,20 +624,26 @@ pub fn interval_example() -> Workout {
 // Standard intervals with one exception:  Go extra long on the first to try to
 // hit a "steady state" for each interval as quickly as possible.
 pub fn create_big_start_interval(
-    warmup: (Duration, u16),
+    warmup: (Duration, Power),
     count: usize,
     start: Duration,
-    high: (Duration, u16),
-    low: (Duration, u16),
-    tail: Option<u16>,
+    high: (Duration, Power),
+    low: (Duration, Power),
+    tail: Option<Power>,
 ) -> Workout {
     Workout::new(
         CycleTree::Node((
             1,
             vec![
-                CycleTree::Leaf(warmup),
-                CycleTree::Leaf((start, high.1)),
-                CycleTree::Node((count - 1, vec![CycleTree::Leaf(low), CycleTree::Leaf(high)])),
+                CycleTree::Leaf((warmup.0, Target::Steady(warmup.1))),
+                CycleTree::Leaf((start, Target::Steady(high.1))),
+                CycleTree::Node((
+                    count - 1,
+                    vec![
+                        CycleTree::Leaf((low.0, Target::Steady(low.1))),
+                        CycleTree::Leaf((high.0, Target::Steady(high.1))),
+                    ],
+                )),
             ],
         )),
         tail,
@@ -136,16 +651,341 @@ pub fn create_big_start_interval(
 }
 
 #[allow(dead_code)]
-// TODO: This could include Set/Add/Sub and then be 50 cycles of Add(15) each 30s
-// Warm up for 5 minutes, then increase power by 15W every 30s until the subject
-// must stop.
-pub fn ramp_test(warmup_power: u16) -> Workout {
-    let mut v = Vec::with_capacity(50);
-    v.push(CycleTree::Leaf((Duration::from_secs(300), warmup_power)));
-    let mut power = 100;
-    for _ in 0..49 {
-        v.push(CycleTree::Leaf((Duration::from_secs(30), warmup_power)));
-        power = power + 15
-    }
-    Workout::new(CycleTree::Node((1, v)), None)
+// Warm up for 5 minutes, then increase power by 15W every 30s (starting from
+// 100W) until the subject must stop.
+pub fn ramp_test(warmup_power: Power) -> Workout {
+    Workout::new(
+        CycleTree::Node((
+            1,
+            vec![
+                CycleTree::Leaf((Duration::from_secs(300), Target::Steady(warmup_power))),
+                CycleTree::Leaf((Duration::from_secs(30), Target::Steady(Power::Watts(100)))),
+                CycleTree::Node((
+                    48,
+                    vec![CycleTree::Leaf((
+                        Duration::from_secs(30),
+                        Target::Add(Power::Watts(15)),
+                    ))],
+                )),
+            ],
+        )),
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_big_start_interval, interval_example, is_within_limits, ramp_test, single_value,
+        Power, Runner, Schedule, Target, Workout, MAX_DURATION,
+    };
+    use crate::cycle_tree::CycleTree;
+    use crate::fit::FitWorkoutStep;
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn to_fit_steps_for_single_value() {
+        assert_eq!(
+            vec![FitWorkoutStep::Power(165, None)],
+            single_value(Power::Watts(165)).to_fit_steps(250)
+        );
+    }
+
+    #[test]
+    fn to_fit_steps_resolves_ftp_percent() {
+        assert_eq!(
+            vec![FitWorkoutStep::Power(173, None)],
+            single_value(Power::FtpPercent(66.5)).to_fit_steps(260)
+        );
+    }
+
+    #[test]
+    fn to_fit_steps_for_big_start_interval() {
+        assert_eq!(
+            vec![
+                FitWorkoutStep::Power(140, Some(Duration::from_secs(300))),
+                FitWorkoutStep::Power(320, Some(Duration::from_secs(150))),
+                FitWorkoutStep::Power(120, Some(Duration::from_secs(90))),
+                FitWorkoutStep::Power(320, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Repeat(2, 13),
+                FitWorkoutStep::Power(160, None),
+            ],
+            create_big_start_interval(
+                (Duration::from_secs(300), Power::Watts(140)),
+                14,
+                Duration::from_secs(150),
+                (Duration::from_secs(60), Power::Watts(320)),
+                (Duration::from_secs(90), Power::Watts(120)),
+                Some(Power::Watts(160)),
+            )
+            .to_fit_steps(250)
+        );
+    }
+
+    #[test]
+    fn to_fit_steps_approximates_ramps() {
+        assert_eq!(
+            vec![
+                FitWorkoutStep::Power(120, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(160, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(190, Some(Duration::from_secs(5))),
+            ],
+            Workout::new(
+                CycleTree::Leaf((
+                    Duration::from_secs(25),
+                    Target::Ramp(Power::FtpPercent(40.0), Power::FtpPercent(80.0)),
+                )),
+                None,
+            )
+            .to_fit_steps(250)
+        );
+    }
+
+    #[test]
+    fn target_at() {
+        let ramp = Target::Ramp(100, 200);
+        let duration = Duration::from_secs(60);
+        let at = |t: Target<u16>, s| t.at(180, Duration::from_secs(s), duration);
+        assert_eq!(at(ramp, 0), 100);
+        assert_eq!(at(ramp, 15), 125);
+        assert_eq!(at(ramp, 90), 200);
+        assert_eq!(at(Target::Ramp(200, 100), 15), 175);
+        assert_eq!(at(Target::Steady(150), 15), 150);
+        assert_eq!(at(Target::Add(15), 15), 195);
+        assert_eq!(at(Target::Sub(15), 15), 165);
+        assert_eq!(at(Target::Sub(200), 15), 0);
+    }
+
+    #[test]
+    fn to_fit_steps_unrolls_relative_repeats() {
+        let step = |target| CycleTree::Leaf((Duration::from_secs(60), target));
+        assert_eq!(
+            vec![
+                FitWorkoutStep::Power(100, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(125, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(115, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(140, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(130, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(150, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(120, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Repeat(5, 2),
+            ],
+            Workout::new(
+                CycleTree::Node((
+                    1,
+                    vec![
+                        step(Target::Steady(Power::Watts(100))),
+                        CycleTree::Node((
+                            2,
+                            vec![
+                                step(Target::Add(Power::Watts(25))),
+                                step(Target::Sub(Power::FtpPercent(4.0))),
+                            ]
+                        )),
+                        CycleTree::Node((
+                            2,
+                            vec![
+                                step(Target::Steady(Power::Watts(150))),
+                                step(Target::Steady(Power::Watts(120))),
+                            ]
+                        )),
+                    ]
+                )),
+                None,
+            )
+            .to_fit_steps(250)
+        );
+    }
+
+    #[test]
+    fn ramp_test_increases_power() {
+        let steps = ramp_test(Power::Watts(120)).to_fit_steps(250);
+        assert_eq!(
+            steps[..3],
+            [
+                FitWorkoutStep::Power(120, Some(Duration::from_secs(300))),
+                FitWorkoutStep::Power(100, Some(Duration::from_secs(30))),
+                FitWorkoutStep::Power(115, Some(Duration::from_secs(30))),
+            ]
+        );
+        assert_eq!(
+            steps.last(),
+            Some(&FitWorkoutStep::Power(820, Some(Duration::from_secs(30))))
+        );
+    }
+
+    #[test]
+    fn is_within_limits_counts_repeats() {
+        let repeat = |count, d| {
+            CycleTree::Node((
+                count,
+                vec![CycleTree::Leaf((d, Target::Steady(Power::Watts(100))))],
+            ))
+        };
+        assert!(is_within_limits(&repeat(48, Duration::from_secs(30))));
+        assert!(is_within_limits(&repeat(2, MAX_DURATION / 2)));
+        assert!(!is_within_limits(&repeat(3, MAX_DURATION / 2)));
+        // Short steps can still add up to too many
+        assert!(!is_within_limits(&CycleTree::Node((
+            1000,
+            vec![repeat(1000, Duration::from_secs(0))]
+        ))));
+        assert!(!is_within_limits(&repeat(
+            usize::max_value(),
+            Duration::from_secs(1)
+        )));
+    }
+
+    #[test]
+    fn runner_can_pause_resume_and_skip() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let schedule = Mutex::new(Schedule::new(start));
+        let mut runner = Runner::new(
+            Workout::new(
+                CycleTree::Node((
+                    1,
+                    vec![
+                        CycleTree::Leaf((
+                            Duration::from_secs(60),
+                            Target::Steady(Power::Watts(100)),
+                        )),
+                        CycleTree::Leaf((
+                            Duration::from_secs(60),
+                            Target::Steady(Power::Watts(200)),
+                        )),
+                    ],
+                )),
+                Some(Power::Watts(50)),
+            ),
+            200,
+        );
+        let powers = RefCell::new(vec![]);
+        let steps = RefCell::new(vec![]);
+        let set_power = |p| {
+            powers.borrow_mut().push(p);
+            true
+        };
+        let on_step = |i, p, d| steps.borrow_mut().push((i, p, d));
+        let progress = || schedule.lock().unwrap().progress.clone().unwrap();
+
+        assert!(runner.tick(&schedule, at(0), &set_power, &on_step));
+        schedule.lock().unwrap().pause(at(10_000));
+        assert!(runner.tick(&schedule, at(11_000), &set_power, &on_step));
+        assert!(runner.tick(&schedule, at(12_000), &set_power, &on_step));
+        let paused = progress();
+        assert_eq!(
+            (paused.step, paused.target, paused.next_target),
+            (0, 100, Some(200))
+        );
+        assert_eq!(paused.elapsed, Duration::from_secs(10));
+        assert!(paused.is_paused);
+        assert!(paused.is_applied);
+
+        schedule.lock().unwrap().resume(at(20_000));
+        schedule.lock().unwrap().extensions += 1;
+        assert!(runner.tick(&schedule, at(20_000), &set_power, &on_step));
+        let extended = progress();
+        assert_eq!(extended.duration, Duration::from_secs(180));
+        assert_eq!(extended.remaining, Some(Duration::from_secs(110)));
+
+        schedule.lock().unwrap().skip = true;
+        assert!(runner.tick(&schedule, at(21_000), &set_power, &on_step));
+        assert_eq!(progress().elapsed, Duration::from_secs(120));
+        schedule.lock().unwrap().skip = true;
+        assert!(runner.tick(&schedule, at(22_000), &set_power, &on_step));
+        let tail = progress();
+        assert_eq!((tail.step, tail.step_count, tail.remaining), (2, 2, None));
+        assert_eq!(tail.fraction(), 1.0);
+
+        schedule.lock().unwrap().running = false;
+        assert!(!runner.tick(&schedule, at(23_000), &set_power, &on_step));
+
+        assert_eq!(*powers.borrow(), vec![100, 80, 100, 200, 50, 50]);
+        assert_eq!(
+            *steps.borrow(),
+            vec![
+                (0, 100, Some(Duration::from_secs(60))),
+                (1, 200, Some(Duration::from_secs(60))),
+                (2, 50, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn runner_limits_ramp_set_points() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let schedule = Mutex::new(Schedule::new(start));
+        let mut runner = Runner::new(
+            Workout::new(
+                CycleTree::Leaf((
+                    Duration::from_secs(10),
+                    Target::Ramp(Power::Watts(100), Power::Watts(200)),
+                )),
+                None,
+            ),
+            200,
+        );
+        let powers = RefCell::new(vec![]);
+        let set_power = |p| {
+            powers.borrow_mut().push(p);
+            true
+        };
+
+        for millis in &[0, 500, 1000, 1500, 5000, 9999] {
+            assert!(runner.tick(&schedule, at(*millis), &set_power, &|_, _, _| ()));
+        }
+        assert_eq!(*powers.borrow(), vec![100, 110, 150, 200]);
+    }
+
+    #[test]
+    fn runner_skips_steps_that_are_already_over() {
+        let start = Instant::now();
+        let schedule = Mutex::new(Schedule::new(start));
+        let mut runner = Runner::new(interval_example(), 200);
+        let steps = RefCell::new(vec![]);
+
+        let on_step = |i, _, _| steps.borrow_mut().push(i);
+
+        let later = start + Duration::from_secs(10 * 60 + 1);
+        assert!(runner.tick(&schedule, later, &|_| true, &on_step));
+        // Into the second of the five hard intervals
+        assert_eq!(*steps.borrow(), vec![3]);
+    }
+
+    #[test]
+    fn runner_retries_unapplied_power() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let schedule = Mutex::new(Schedule::new(start));
+        let mut runner = Runner::new(single_value(Power::Watts(100)), 200);
+        let powers = RefCell::new(vec![]);
+        // The trainer ignores us the first time
+        let set_power = |p| {
+            let mut powers = powers.borrow_mut();
+            powers.push(p);
+            powers.len() > 1
+        };
+        let is_applied = || {
+            schedule
+                .lock()
+                .unwrap()
+                .progress
+                .clone()
+                .unwrap()
+                .is_applied
+        };
+
+        assert!(runner.tick(&schedule, at(0), &set_power, &|_, _, _| ()));
+        assert!(runner.tick(&schedule, at(500), &set_power, &|_, _, _| ()));
+        assert!(!is_applied());
+        assert!(runner.tick(&schedule, at(1000), &set_power, &|_, _, _| ()));
+        assert!(runner.tick(&schedule, at(2000), &set_power, &|_, _, _| ()));
+        assert!(is_applied());
+
+        assert_eq!(*powers.borrow(), vec![100, 100]);
+    }
 }
@@ -0,0 +1,422 @@
+use crate::cycle_tree::CycleTree;
+use crate::workout::{self, Power, Target};
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, digit1, multispace0, multispace1, one_of},
+    combinator::{all_consuming, cut, map, map_res, opt},
+    error::{context, ErrorKind, VerboseError, VerboseErrorKind},
+    multi::{many1, separated_nonempty_list},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    Err, IResult,
+};
+use std::str::FromStr;
+use std::time::Duration;
+
+// A compact way to write workouts, for example:
+//   10m@120, 5x(3m@300, 2m@120), 10m@100, tail 90
+// Each step is a duration (made of hours, minutes and seconds, like 1m30s)
+// held at a power (in watts, or percent of FTP like 65%), ramped between two
+// powers (like 100-200), or changed relative to the step before (like +15 or
+// -5%).  Steps can be repeated with Nx(...), and an optional tail power can be
+// held indefinitely at the end.
+
+#[derive(Debug, PartialEq)]
+pub struct DslError {
+    // Offset into the input (in bytes) where parsing failed
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for DslError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for DslError {}
+
+type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+
+type Step = CycleTree<(Duration, Target<Power>)>;
+
+fn ws<'a, T, F>(f: F) -> impl Fn(&'a str) -> ParseResult<'a, T>
+where
+    F: Fn(&'a str) -> ParseResult<'a, T>,
+{
+    delimited(multispace0, f, multispace0)
+}
+
+fn duration(i: &str) -> ParseResult<Duration> {
+    let (rest, parts) = context(
+        "duration",
+        many1(pair(map_res(digit1, u64::from_str), one_of("hms"))),
+    )(i)?;
+    let seconds = parts.iter().try_fold(0u64, |total, (n, unit)| {
+        let seconds = match unit {
+            'h' => n.checked_mul(3600),
+            'm' => n.checked_mul(60),
+            _ => Some(*n),
+        }?;
+        total.checked_add(seconds)
+    });
+    match seconds {
+        Some(seconds) => Ok((rest, Duration::from_secs(seconds))),
+        // It's a duration, just not one we can represent, so there's no point
+        // trying anything else
+        None => Err(Err::Failure(VerboseError {
+            errors: vec![(i, VerboseErrorKind::Context("a shorter duration"))],
+        })),
+    }
+}
+
+fn power(i: &str) -> ParseResult<Power> {
+    context(
+        "power",
+        alt((
+            map(
+                terminated(map_res(digit1, f32::from_str), char('%')),
+                Power::FtpPercent,
+            ),
+            map(
+                terminated(map_res(digit1, u16::from_str), opt(one_of("wW"))),
+                Power::Watts,
+            ),
+        )),
+    )(i)
+}
+
+fn step(i: &str) -> ParseResult<Step> {
+    map(
+        separated_pair(
+            duration,
+            char('@'),
+            cut(alt((
+                map(preceded(char('+'), power), Target::Add),
+                map(preceded(char('-'), power), Target::Sub),
+                map(
+                    pair(power, opt(preceded(char('-'), cut(power)))),
+                    |(from, to)| match to {
+                        Some(to) => Target::Ramp(from, to),
+                        None => Target::Steady(from),
+                    },
+                ),
+            ))),
+        ),
+        CycleTree::Leaf,
+    )(i)
+}
+
+fn repeat(i: &str) -> ParseResult<Step> {
+    let (rest, count) = terminated(map_res(digit1, usize::from_str), char('x'))(i)?;
+    if count > workout::MAX_REPEATS {
+        return Err(Err::Failure(VerboseError {
+            errors: vec![(i, VerboseErrorKind::Context("fewer repeats"))],
+        }));
+    }
+    map(
+        cut(delimited(ws(char('(')), steps, ws(char(')')))),
+        move |s| CycleTree::Node((count, s)),
+    )(rest)
+}
+
+fn steps(i: &str) -> ParseResult<Vec<Step>> {
+    separated_nonempty_list(ws(char(',')), alt((repeat, step)))(i)
+}
+
+fn tail(i: &str) -> ParseResult<Power> {
+    preceded(pair(tag("tail"), multispace1), cut(power))(i)
+}
+
+fn workout(i: &str) -> ParseResult<(Vec<Step>, Option<Power>)> {
+    all_consuming(ws(alt((
+        map(tail, |t| (vec![], Some(t))),
+        pair(steps, opt(preceded(ws(char(',')), tail))),
+    ))))(i)
+}
+
+fn to_dsl_error(input: &str, e: VerboseError<&str>) -> DslError {
+    // The first error is the deepest, so it has the most precise position,
+    // but the context that explains it may be further up.
+    let position = e
+        .errors
+        .first()
+        .map(|(rest, _)| input.len() - rest.len())
+        .unwrap_or(0);
+    let context = e.errors.iter().find_map(|(_, kind)| match kind {
+        VerboseErrorKind::Context(c) => Some(*c),
+        _ => None,
+    });
+    let message = match (context, e.errors.first().map(|x| &x.1)) {
+        (Some(c), _) => format!("Expected {}", c),
+        (None, Some(VerboseErrorKind::Char(c))) => format!("Expected '{}'", c),
+        (None, Some(VerboseErrorKind::Nom(ErrorKind::Eof))) => {
+            "Expected ',' or the end of the workout".to_string()
+        }
+        _ => "Invalid workout".to_string(),
+    };
+    DslError { position, message }
+}
+
+pub fn parse_workout(
+    input: &str,
+) -> Result<(CycleTree<(Duration, Target<Power>)>, Option<Power>), DslError> {
+    match workout(input) {
+        Ok((_, (steps, tail))) => {
+            let ct = CycleTree::Node((1, steps));
+            // Nothing is expanded while parsing, so we can check it all at once
+            if workout::is_within_limits(&ct) {
+                Ok((ct, tail))
+            } else {
+                Err(DslError {
+                    position: 0,
+                    message: "Workout is too long".to_string(),
+                })
+            }
+        }
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(to_dsl_error(input, e)),
+        // We only use complete parsers
+        Err(Err::Incomplete(_)) => Err(DslError {
+            position: input.len(),
+            message: "Unexpected end of workout".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_workout, DslError};
+    use crate::cycle_tree::CycleTree::{Leaf, Node};
+    use crate::workout::{Power, Target};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_workout_with_repeats_and_tail() {
+        assert_eq!(
+            parse_workout("10m@120, 5x(3m@300, 2m@120), 10m@100, tail 90"),
+            Ok((
+                Node((
+                    1,
+                    vec![
+                        Leaf((Duration::from_secs(600), Target::Steady(Power::Watts(120)))),
+                        Node((
+                            5,
+                            vec![
+                                Leaf((Duration::from_secs(180), Target::Steady(Power::Watts(300)))),
+                                Leaf((Duration::from_secs(120), Target::Steady(Power::Watts(120)))),
+                            ]
+                        )),
+                        Leaf((Duration::from_secs(600), Target::Steady(Power::Watts(100)))),
+                    ]
+                )),
+                Some(Power::Watts(90))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_workout_nested_compound_durations_and_whitespace() {
+        assert_eq!(
+            parse_workout(" 1h@100W ,2x( 3x(1m30s@250,30s@100) )\n"),
+            Ok((
+                Node((
+                    1,
+                    vec![
+                        Leaf((Duration::from_secs(3600), Target::Steady(Power::Watts(100)))),
+                        Node((
+                            2,
+                            vec![Node((
+                                3,
+                                vec![
+                                    Leaf((
+                                        Duration::from_secs(90),
+                                        Target::Steady(Power::Watts(250))
+                                    )),
+                                    Leaf((
+                                        Duration::from_secs(30),
+                                        Target::Steady(Power::Watts(100))
+                                    )),
+                                ]
+                            ))]
+                        )),
+                    ]
+                )),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_workout_ftp_percent() {
+        assert_eq!(
+            parse_workout("20m@55%, tail 50%"),
+            Ok((
+                Node((
+                    1,
+                    vec![Leaf((
+                        Duration::from_secs(1200),
+                        Target::Steady(Power::FtpPercent(55.0))
+                    ))]
+                )),
+                Some(Power::FtpPercent(50.0))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_workout_ramp() {
+        assert_eq!(
+            parse_workout("10m@40%-75%, 5m@200-100w"),
+            Ok((
+                Node((
+                    1,
+                    vec![
+                        Leaf((
+                            Duration::from_secs(600),
+                            Target::Ramp(Power::FtpPercent(40.0), Power::FtpPercent(75.0))
+                        )),
+                        Leaf((
+                            Duration::from_secs(300),
+                            Target::Ramp(Power::Watts(200), Power::Watts(100))
+                        )),
+                    ]
+                )),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_workout_relative() {
+        assert_eq!(
+            parse_workout("30s@100, 3x(30s@+15, 30s@-5%)"),
+            Ok((
+                Node((
+                    1,
+                    vec![
+                        Leaf((Duration::from_secs(30), Target::Steady(Power::Watts(100)))),
+                        Node((
+                            3,
+                            vec![
+                                Leaf((Duration::from_secs(30), Target::Add(Power::Watts(15)))),
+                                Leaf((
+                                    Duration::from_secs(30),
+                                    Target::Sub(Power::FtpPercent(5.0))
+                                )),
+                            ]
+                        )),
+                    ]
+                )),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_workout_ramp_missing_power() {
+        assert_eq!(
+            parse_workout("10m@100-"),
+            Err(DslError {
+                position: 8,
+                message: "Expected power".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_duration_overflow() {
+        assert_eq!(
+            parse_workout("10m@120, 99999999999999999h@100"),
+            Err(DslError {
+                position: 9,
+                message: "Expected a shorter duration".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_too_many_repeats() {
+        assert_eq!(
+            parse_workout("10m@120, 1000000000x(1s@+1)"),
+            Err(DslError {
+                position: 9,
+                message: "Expected fewer repeats".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_too_long() {
+        let too_long = Err(DslError {
+            position: 0,
+            message: "Workout is too long".to_string(),
+        });
+        assert_eq!(parse_workout("1000x(1000x(1s@+1))"), too_long);
+        assert_eq!(parse_workout("1000x(1000x(0s@100))"), too_long);
+        assert_eq!(parse_workout("20h@100, 5h@100"), too_long);
+        assert!(parse_workout("1000x(30s@+1, 30s@-1)").is_ok());
+    }
+
+    #[test]
+    fn parse_workout_only_tail() {
+        assert_eq!(
+            parse_workout("tail 150"),
+            Ok((Node((1, vec![])), Some(Power::Watts(150))))
+        );
+    }
+
+    #[test]
+    fn parse_workout_missing_power() {
+        assert_eq!(
+            parse_workout("10m@120, 5m@"),
+            Err(DslError {
+                position: 12,
+                message: "Expected power".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_unclosed_repeat() {
+        assert_eq!(
+            parse_workout("5x(3m@300, 2m@120"),
+            Err(DslError {
+                position: 17,
+                message: "Expected ')'".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_missing_separator() {
+        assert_eq!(
+            parse_workout("10m@120 5m@100"),
+            Err(DslError {
+                position: 8,
+                message: "Expected ',' or the end of the workout".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_bad_duration() {
+        assert_eq!(
+            parse_workout("10q@120"),
+            Err(DslError {
+                position: 2,
+                message: "Expected duration".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_workout_tail_must_be_last() {
+        assert_eq!(
+            parse_workout("tail 90, 10m@100"),
+            Err(DslError {
+                position: 7,
+                message: "Expected ',' or the end of the workout".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use crate::cycle_tree::CycleTree;
+use crate::workout::{self, Power, Target, Workout};
+use std::time::Duration;
+
+// Anything past these is a broken file rather than a hard workout
+const MAX_STEP_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+const MAX_FTP_FRACTION: f32 = 10.0;
+
+#[derive(Debug, PartialEq)]
+pub enum ZwoError {
+    Malformed(usize),
+    MissingWorkout,
+    MissingAttribute(String, String),
+    InvalidAttribute(String, String, String),
+    Unsupported(String),
+    TooLong,
+}
+
+impl std::fmt::Display for ZwoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZwoError::Malformed(offset) => write!(f, "Malformed XML at byte {}", offset),
+            ZwoError::MissingWorkout => write!(f, "No workout element"),
+            ZwoError::MissingAttribute(element, attribute) => {
+                write!(f, "{} is missing {}", element, attribute)
+            }
+            ZwoError::InvalidAttribute(element, attribute, value) => {
+                write!(f, "{} has invalid {}: {}", element, attribute, value)
+            }
+            ZwoError::Unsupported(element) => write!(f, "{} has no power target", element),
+            ZwoError::TooLong => write!(f, "Workout is too long"),
+        }
+    }
+}
+
+impl std::error::Error for ZwoError {}
+
+// Just enough XML to read ZWO files:  tags, attributes, and the text that
+// follows an opening tag.  Comments, declarations and closing tags are skipped
+// over entirely, and everything after the end of the workout is ignored.
+#[derive(Debug, PartialEq)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &**v)
+    }
+
+    fn parse_attribute<T: std::str::FromStr>(&self, name: &str) -> Result<T, ZwoError> {
+        let value = self
+            .attribute(name)
+            .ok_or_else(|| ZwoError::MissingAttribute(self.name.clone(), name.to_string()))?;
+        value.trim().parse().map_err(|_| {
+            ZwoError::InvalidAttribute(self.name.clone(), name.to_string(), value.to_string())
+        })
+    }
+
+    fn duration(&self, name: &str) -> Result<Duration, ZwoError> {
+        let seconds: f64 = self.parse_attribute(name)?;
+        if seconds.is_finite() && seconds >= 0.0 && seconds <= MAX_STEP_SECONDS {
+            Ok(Duration::from_secs_f64(seconds))
+        } else {
+            Err(ZwoError::InvalidAttribute(
+                self.name.clone(),
+                name.to_string(),
+                seconds.to_string(),
+            ))
+        }
+    }
+
+    // ZWO power is a fraction of FTP, which we keep as a percent
+    fn power(&self, name: &str) -> Result<Power, ZwoError> {
+        let fraction: f32 = self.parse_attribute(name)?;
+        if fraction.is_finite() && fraction >= 0.0 && fraction <= MAX_FTP_FRACTION {
+            Ok(Power::FtpPercent(fraction * 100.0))
+        } else {
+            Err(ZwoError::InvalidAttribute(
+                self.name.clone(),
+                name.to_string(),
+                fraction.to_string(),
+            ))
+        }
+    }
+
+    fn steady(
+        &self,
+        duration: &str,
+        power: &str,
+    ) -> Result<CycleTree<(Duration, Target<Power>)>, ZwoError> {
+        Ok(CycleTree::Leaf((
+            self.duration(duration)?,
+            Target::Steady(self.power(power)?),
+        )))
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn parse_attributes(s: &str, offset: usize) -> Result<Vec<(String, String)>, ZwoError> {
+    let mut attributes = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=').ok_or(ZwoError::Malformed(offset))?;
+        let key = rest[..eq].trim();
+        let after_eq = rest[eq + 1..].trim_start();
+        let quote = after_eq.chars().next().ok_or(ZwoError::Malformed(offset))?;
+        if quote != '"' && quote != '\'' {
+            return Err(ZwoError::Malformed(offset));
+        }
+        let end = after_eq[1..]
+            .find(quote)
+            .ok_or(ZwoError::Malformed(offset))?;
+        attributes.push((key.to_string(), unescape(&after_eq[1..end + 1])));
+        rest = after_eq[end + 2..].trim_start();
+    }
+    Ok(attributes)
+}
+
+// The end of the tag that s starts with, skipping over any '>' in quoted
+// attribute values
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_elements(xml: &str) -> Result<Vec<Element>, ZwoError> {
+    let mut elements = Vec::new();
+    let mut i = 0;
+    while let Some(start) = xml[i..].find('<').map(|x| x + i) {
+        let rest = &xml[start..];
+        if rest.starts_with("<!--") {
+            i = start + rest.find("-->").ok_or(ZwoError::Malformed(start))? + 3;
+            continue;
+        }
+        let end = start + find_tag_end(rest).ok_or(ZwoError::Malformed(start))?;
+        i = end + 1;
+        if rest.starts_with("</") {
+            if xml[start + 2..end].trim().eq_ignore_ascii_case("workout") {
+                break;
+            }
+            continue;
+        }
+        if rest.starts_with("<?") || rest.starts_with("<!") {
+            continue;
+        }
+
+        let inner = xml[start + 1..end].trim_end_matches('/');
+        let name_end = inner
+            .find(|c: char| c.is_whitespace())
+            .unwrap_or(inner.len());
+        let name = &inner[..name_end];
+        if name.is_empty() {
+            return Err(ZwoError::Malformed(start));
+        }
+        let text_end = xml[i..].find('<').map(|x| x + i).unwrap_or(xml.len());
+        elements.push(Element {
+            name: name.to_string(),
+            attributes: parse_attributes(&inner[name_end..], start)?,
+            text: unescape(xml[i..text_end].trim()),
+        });
+    }
+    Ok(elements)
+}
+
+// Parses a Zwift workout file into its name and a Workout, where all power is
+// relative to FTP.  Elements we don't understand (like text events) are
+// ignored.  Free rides and max efforts are rejected, since ERG mode can only
+// hold a power, and holding any power would get in the rider's way.
+pub fn parse_zwo(xml: &str) -> Result<(String, Workout), ZwoError> {
+    let elements = parse_elements(xml)?;
+    let name = elements
+        .iter()
+        .find(|e| e.name.eq_ignore_ascii_case("name"))
+        .map(|e| e.text.clone())
+        .unwrap_or_default();
+    let workout_index = elements
+        .iter()
+        .position(|e| e.name.eq_ignore_ascii_case("workout"))
+        .ok_or(ZwoError::MissingWorkout)?;
+
+    let mut steps = Vec::new();
+    for e in &elements[workout_index + 1..] {
+        match &*e.name.to_ascii_lowercase() {
+            "steadystate" => steps.push(e.steady("Duration", "Power")?),
+            "intervalst" => {
+                let repeat: usize = e.parse_attribute("Repeat")?;
+                if repeat > workout::MAX_REPEATS {
+                    return Err(ZwoError::InvalidAttribute(
+                        e.name.clone(),
+                        "Repeat".to_string(),
+                        repeat.to_string(),
+                    ));
+                }
+                steps.push(CycleTree::Node((
+                    repeat,
+                    vec![
+                        e.steady("OnDuration", "OnPower")?,
+                        e.steady("OffDuration", "OffPower")?,
+                    ],
+                )))
+            }
+            // PowerLow is always where the ramp starts, even for cool downs
+            "warmup" | "cooldown" | "ramp" => steps.push(CycleTree::Leaf((
+                e.duration("Duration")?,
+                Target::Ramp(e.power("PowerLow")?, e.power("PowerHigh")?),
+            ))),
+            "freeride" | "maxeffort" => return Err(ZwoError::Unsupported(e.name.clone())),
+            _ => {}
+        }
+    }
+
+    let ct = CycleTree::Node((1, steps));
+    if !workout::is_within_limits(&ct) {
+        return Err(ZwoError::TooLong);
+    }
+    Ok((name, Workout::new(ct, None)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_zwo, ZwoError};
+    use crate::fit::FitWorkoutStep;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_zwo_steady_state_and_intervals() {
+        let (name, workout) = parse_zwo(
+            r#"<?xml version="1.0"?>
+<workout_file>
+    <author>Coach</author>
+    <name>Over &amp; Unders</name>
+    <sportType>bike</sportType>
+    <workout>
+        <!-- <SteadyState Duration="1" Power="9"/> -->
+        <SteadyState Duration="300" Power="0.5"/>
+        <IntervalsT Repeat="3" OnDuration="60" OffDuration="30" OnPower="1.2" OffPower="0.4">
+            <textevent timeoffset="0" message="Go!"/>
+        </IntervalsT>
+    </workout>
+</workout_file>"#,
+        )
+        .unwrap();
+
+        assert_eq!(name, "Over & Unders");
+        assert_eq!(
+            workout.to_fit_steps(250),
+            vec![
+                FitWorkoutStep::Power(125, Some(Duration::from_secs(300))),
+                FitWorkoutStep::Power(300, Some(Duration::from_secs(60))),
+                FitWorkoutStep::Power(100, Some(Duration::from_secs(30))),
+                FitWorkoutStep::Repeat(1, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_zwo_ramps_are_stepped() {
+        let (_, workout) = parse_zwo(
+            r#"<workout_file><workout>
+                <Warmup Duration="25" PowerLow="0.5" PowerHigh="1.0"/>
+                <Cooldown Duration="20" PowerLow="1.0" PowerHigh="0.5"/>
+            </workout></workout_file>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            workout.to_fit_steps(200),
+            vec![
+                FitWorkoutStep::Power(120, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(160, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(190, Some(Duration::from_secs(5))),
+                FitWorkoutStep::Power(175, Some(Duration::from_secs(10))),
+                FitWorkoutStep::Power(125, Some(Duration::from_secs(10))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_zwo_missing_attribute() {
+        assert_eq!(
+            parse_zwo("<workout_file><workout><SteadyState Power='0.5'/></workout></workout_file>")
+                .err(),
+            Some(ZwoError::MissingAttribute(
+                "SteadyState".to_string(),
+                "Duration".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_zwo_invalid_attribute() {
+        assert_eq!(
+            parse_zwo(
+                "<workout_file><workout><SteadyState Duration='x' Power='0.5'/></workout></workout_file>"
+            )
+            .err(),
+            Some(ZwoError::InvalidAttribute(
+                "SteadyState".to_string(),
+                "Duration".to_string(),
+                "x".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_zwo_out_of_range_attributes() {
+        assert_eq!(
+            parse_zwo(
+                "<workout_file><workout><SteadyState Duration='inf' Power='0.5'/></workout></workout_file>"
+            )
+            .err(),
+            Some(ZwoError::InvalidAttribute(
+                "SteadyState".to_string(),
+                "Duration".to_string(),
+                "inf".to_string()
+            ))
+        );
+        assert!(parse_zwo(
+            "<workout_file><workout><SteadyState Duration='1e300' Power='0.5'/></workout></workout_file>"
+        )
+        .is_err());
+        assert_eq!(
+            parse_zwo(
+                "<workout_file><workout><SteadyState Duration='60' Power='NaN'/></workout></workout_file>"
+            )
+            .err(),
+            Some(ZwoError::InvalidAttribute(
+                "SteadyState".to_string(),
+                "Power".to_string(),
+                "NaN".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_zwo_ignores_quoted_brackets_and_anything_after_the_workout() {
+        let (_, workout) = parse_zwo(
+            r#"<workout_file><workout>
+                <SteadyState Duration="60" Power="0.5">
+                    <textevent timeoffset="0" message="Hold > 100W"/>
+                </SteadyState>
+            </workout>
+            <SteadyState Duration="60" Power="2.0"/>
+            </workout_file>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            workout.to_fit_steps(200),
+            vec![FitWorkoutStep::Power(100, Some(Duration::from_secs(60)))]
+        );
+    }
+
+    #[test]
+    fn parse_zwo_too_many_repeats() {
+        assert_eq!(
+            parse_zwo(
+                "<workout_file><workout><IntervalsT Repeat='1000000000' OnDuration='1' OffDuration='1' OnPower='1' OffPower='0.5'/></workout></workout_file>"
+            )
+            .err(),
+            Some(ZwoError::InvalidAttribute(
+                "IntervalsT".to_string(),
+                "Repeat".to_string(),
+                "1000000000".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_zwo(
+                "<workout_file><workout><IntervalsT Repeat='1000' OnDuration='60' OffDuration='30' OnPower='1' OffPower='0.5'/></workout></workout_file>"
+            )
+            .err(),
+            Some(ZwoError::TooLong)
+        );
+    }
+
+    #[test]
+    fn parse_zwo_free_ride_is_unsupported() {
+        assert_eq!(
+            parse_zwo("<workout_file><workout><FreeRide Duration='120'/></workout></workout_file>")
+                .err(),
+            Some(ZwoError::Unsupported("FreeRide".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_zwo_missing_workout() {
+        assert_eq!(
+            parse_zwo("<workout_file></workout_file>").err(),
+            Some(ZwoError::MissingWorkout)
+        );
+    }
+
+    #[test]
+    fn parse_zwo_malformed() {
+        assert_eq!(
+            parse_zwo("<workout_file><workout").err(),
+            Some(ZwoError::Malformed(14))
+        );
+    }
+}